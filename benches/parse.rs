@@ -0,0 +1,66 @@
+use std::fmt::Write;
+use std::hint::black_box;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use kaleidoscope::arena::parse_str_arena;
+use kaleidoscope::{Lexer, Token, parse_str};
+
+// n 个函数定义, 每个函数体是一棵较深的表达式树, 最后调用所有函数
+fn generate_program(functions: usize) -> String {
+    let mut source = String::new();
+    for i in 0..functions {
+        write!(source, "def f{}(x y) ", i).unwrap();
+        for j in 0..20 {
+            write!(source, "(x * {} + y - f{}(x, {})) < ", j, i, j).unwrap();
+        }
+        source.push_str("x;\n");
+    }
+    let calls: Vec<String> = (0..functions).map(|i| format!("f{}(1, 2)", i)).collect();
+    source.push_str(&calls.join(" + "));
+    source
+}
+
+// 同样的输入, 每个节点一个 Arc 和所有表达式在一个 arena 中; 都包括释放 AST
+fn bench_parse(c: &mut Criterion) {
+    let source = generate_program(500);
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("parse_str", |b| {
+        b.iter(|| parse_str(black_box(&source)).unwrap())
+    });
+    group.bench_function("parse_str_arena", |b| {
+        b.iter(|| parse_str_arena(black_box(&source)).unwrap())
+    });
+    group.finish();
+}
+
+// 同样的输入和同样的循环, 分别从 Read 读取和直接在 &str 上分析
+fn bench_lex(c: &mut Criterion) {
+    let source = generate_program(500);
+    let mut group = c.benchmark_group("lex");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("reader", |b| {
+        b.iter(|| {
+            let mut lexer = Lexer::new(black_box(source.as_bytes())).unwrap();
+            let mut names = 0;
+            while lexer.update_token() != Token::Eof {
+                names += lexer.identifier_text().len();
+            }
+            names
+        })
+    });
+    group.bench_function("str", |b| {
+        b.iter(|| {
+            let mut lexer = Lexer::from_str(black_box(&source));
+            let mut names = 0;
+            while lexer.update_token() != Token::Eof {
+                names += lexer.identifier_text().len();
+            }
+            names
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_lex);
+criterion_main!(benches);
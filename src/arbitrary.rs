@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use crate::intern::Symbol;
+use crate::{
+    ArrayExprAST, BinaryExprAST, CallExprAST, ClosureExprAST, ExprAST, FunctionAST, GlobalAST,
+    IndexExprAST, KEYWORDS, NumberExprAST, Operator, Program, PrototypeAST, TopLevelItem, Type,
+    VariableExprAST,
+};
+
+// Random ASTs for property tests. Everything generated here can be printed
+// by `format::print_program` and parsed back into an equal tree, so
+// `parse(print(ast)) == ast` must hold for every generated program.
+
+// 短的名字; 上下文关键字也可以作为名字, 保留字不行
+pub fn arb_name() -> impl Strategy<Value = Symbol> {
+    prop_oneof![
+        "[a-z][a-z0-9]{0,4}",
+        prop::sample::select(vec!["in", "global", "import"]).prop_map(String::from),
+    ]
+    .prop_filter("reserved words are not names", |name| {
+        KEYWORDS
+            .iter()
+            .all(|(word, tok)| word != name || tok.is_name())
+    })
+    .prop_map(Symbol::from)
+}
+
+// 没有负数字面量, 负数要写成 0 - x; 溢出的字面量是无穷大
+pub fn arb_number() -> impl Strategy<Value = f64> {
+    prop_oneof![
+        (0u32..1000).prop_map(f64::from),
+        0.0f64..1e6,
+        1e15f64..f64::MAX,
+        f64::MIN_POSITIVE..1e-4,
+        Just(f64::INFINITY),
+    ]
+}
+
+pub fn arb_type() -> impl Strategy<Value = Type> {
+    prop_oneof![Just(Type::Double), Just(Type::Bool), Just(Type::Array)]
+}
+
+pub fn arb_binop() -> impl Strategy<Value = char> {
+    prop::sample::select(Operator::ALL.map(Operator::as_char).to_vec())
+}
+
+pub fn arb_prototype() -> impl Strategy<Value = PrototypeAST> {
+    (arb_name(), vec((arb_name(), arb_type()), 0..3), arb_type()).prop_map(
+        |(name, args, return_type)| {
+            let (args, arg_types) = args.into_iter().unzip();
+            PrototypeAST::with_types(name, args, arg_types, return_type)
+        },
+    )
+}
+
+// Expressions of every kind, nested a few levels deep.
+pub fn arb_expr() -> impl Strategy<Value = Arc<dyn ExprAST>> {
+    let leaf = prop_oneof![
+        arb_number().prop_map(|val| Arc::new(NumberExprAST::new(val)) as Arc<dyn ExprAST>),
+        arb_name().prop_map(|name| Arc::new(VariableExprAST::new(name)) as Arc<dyn ExprAST>),
+    ];
+    leaf.prop_recursive(4, 32, 3, |inner| {
+        prop_oneof![
+            (arb_binop(), inner.clone(), inner.clone()).prop_map(|(op, lhs, rhs)| {
+                Arc::new(BinaryExprAST::new(op, lhs, rhs)) as Arc<dyn ExprAST>
+            }),
+            (arb_name(), vec(inner.clone(), 0..3)).prop_map(|(callee, args)| {
+                Arc::new(CallExprAST::new(callee, args)) as Arc<dyn ExprAST>
+            }),
+            vec(inner.clone(), 0..3)
+                .prop_map(|elements| Arc::new(ArrayExprAST::new(elements)) as Arc<dyn ExprAST>),
+            (inner.clone(), inner.clone()).prop_map(|(array, index)| {
+                Arc::new(IndexExprAST::new(array, index)) as Arc<dyn ExprAST>
+            }),
+            (arb_prototype(), inner.clone(), inner).prop_map(|(proto, function_body, body)| {
+                let function = Arc::new(FunctionAST::new(Arc::new(proto), function_body));
+                Arc::new(ClosureExprAST::new(function, body)) as Arc<dyn ExprAST>
+            }),
+        ]
+    })
+}
+
+// 顶层表达式先用占位的名字, 由 arb_program 按顺序编号
+pub fn arb_item() -> impl Strategy<Value = TopLevelItem> {
+    prop_oneof![
+        (arb_prototype(), arb_expr()).prop_map(|(proto, body)| {
+            TopLevelItem::Def(Arc::new(FunctionAST::new(Arc::new(proto), body)))
+        }),
+        (arb_prototype(), any::<bool>()).prop_map(|(proto, varargs)| {
+            TopLevelItem::Extern(Arc::new(proto.with_varargs(varargs)))
+        }),
+        (arb_name(), arb_expr())
+            .prop_map(|(name, init)| TopLevelItem::Global(Arc::new(GlobalAST::new(name, init)))),
+        arb_expr().prop_map(|body| anonymous(0, body)),
+    ]
+}
+
+pub fn arb_program() -> impl Strategy<Value = Program> {
+    vec(arb_item(), 0..5).prop_map(|items| {
+        // 和 parser 一样, 匿名函数依次叫 __anon_expr0, __anon_expr1, ...
+        let mut count = 0;
+        let items = items
+            .into_iter()
+            .map(|item| match item {
+                TopLevelItem::Expr(function) => {
+                    count += 1;
+                    anonymous(count - 1, function.body().clone())
+                }
+                item => item,
+            })
+            .collect();
+        Program::new(items)
+    })
+}
+
+fn anonymous(n: usize, body: Arc<dyn ExprAST>) -> TopLevelItem {
+    let proto = PrototypeAST::new(format!("__anon_expr{}", n), Vec::new());
+    TopLevelItem::Expr(Arc::new(FunctionAST::new(Arc::new(proto), body)))
+}
+
+#[cfg(test)]
+mod test_arbitrary {
+    use super::*;
+    use crate::format::print_program;
+    use crate::parse_str;
+
+    proptest! {
+        #[test]
+        fn test_print_parse_round_trip(program in arb_program()) {
+            let printed = print_program(&program);
+            let parsed = parse_str(&printed);
+            prop_assert!(parsed.is_ok(), "{:?} in:\n{}", parsed, printed);
+            prop_assert_eq!(parsed.unwrap(), program, "printed as:\n{}", printed);
+        }
+    }
+}
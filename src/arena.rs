@@ -0,0 +1,374 @@
+use std::ops::Index;
+use std::sync::Arc;
+
+use crate::intern::{Interner, Symbol};
+use crate::{ASTParser, AstBuilder, Lexer, ParseError, PrototypeAST, Span};
+
+// Handle of an expression stored in an `AstArena`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprId(u32);
+
+// Arguments of a call or elements of an array, stored one after the other
+// in the arena instead of in a Vec of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExprList {
+    start: u32,
+    len: u32,
+}
+
+// Expression node of the arena AST; children are referred to by id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Variable(Symbol),
+    Binary {
+        op: char,
+        lhs: ExprId,
+        rhs: ExprId,
+    },
+    Call {
+        callee: Symbol,
+        args: ExprList,
+        callee_span: Span,
+    },
+    Array(ExprList),
+    Index {
+        array: ExprId,
+        index: ExprId,
+    },
+    // def proto function_body in body
+    Closure {
+        proto: Arc<PrototypeAST>,
+        function_body: ExprId,
+        body: ExprId,
+    },
+    // 解析失败的表达式, 同 ErrorAST
+    Error(Box<ParseError>),
+}
+
+// All expressions of a program in a few Vecs instead of one `Arc` per
+// node, so building and dropping a large tree costs a few big allocations.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AstArena {
+    exprs: Vec<Expr>,
+    spans: Vec<Option<Span>>, // 和 exprs 一一对应
+    lists: Vec<ExprId>,       // ExprList 指向这里
+}
+impl AstArena {
+    pub fn new() -> Self {
+        AstArena::default()
+    }
+    pub fn alloc(&mut self, expr: Expr, span: Option<Span>) -> ExprId {
+        let id = ExprId(self.exprs.len() as u32);
+        self.exprs.push(expr);
+        self.spans.push(span);
+        id
+    }
+    pub fn alloc_list(&mut self, ids: impl IntoIterator<Item = ExprId>) -> ExprList {
+        let start = self.lists.len();
+        self.lists.extend(ids);
+        let len = self.lists.len() - start;
+        ExprList {
+            start: start as u32,
+            len: len as u32,
+        }
+    }
+    pub fn get(&self, id: ExprId) -> &Expr {
+        &self.exprs[id.0 as usize]
+    }
+    pub fn span(&self, id: ExprId) -> Option<Span> {
+        self.spans[id.0 as usize]
+    }
+    pub fn list(&self, list: ExprList) -> &[ExprId] {
+        let start = list.start as usize;
+        &self.lists[start..start + list.len as usize]
+    }
+    pub fn len(&self) -> usize {
+        self.exprs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.exprs.is_empty()
+    }
+}
+impl Index<ExprId> for AstArena {
+    type Output = Expr;
+    fn index(&self, id: ExprId) -> &Expr {
+        self.get(id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArenaFunction {
+    pub proto: Arc<PrototypeAST>,
+    pub body: ExprId,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArenaGlobal {
+    pub name: Symbol,
+    pub init: ExprId,
+    pub span: Span,
+}
+
+// 同 TopLevelItem, 表达式在 AstArena 中
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArenaItem {
+    Def(ArenaFunction),
+    Extern(Arc<PrototypeAST>),
+    Global(ArenaGlobal),
+    Expr(ArenaFunction), // 包装成匿名函数 __anon_exprN
+}
+
+// Lets `ASTParser` allocate every expression it parses into one arena.
+#[derive(Debug, Default)]
+pub struct ArenaBuilder {
+    arena: AstArena,
+}
+impl ArenaBuilder {
+    pub fn new() -> Self {
+        ArenaBuilder::default()
+    }
+    pub fn arena(&self) -> &AstArena {
+        &self.arena
+    }
+    pub fn into_arena(self) -> AstArena {
+        self.arena
+    }
+}
+impl AstBuilder for ArenaBuilder {
+    type Expr = ExprId;
+    type Function = ArenaFunction;
+    type Global = ArenaGlobal;
+    type Item = ArenaItem;
+
+    fn number(&mut self, val: f64, span: Span) -> ExprId {
+        self.arena.alloc(Expr::Number(val), Some(span))
+    }
+    fn variable(&mut self, name: Symbol, span: Span) -> ExprId {
+        self.arena.alloc(Expr::Variable(name), Some(span))
+    }
+    fn binary(&mut self, op: char, lhs: ExprId, rhs: ExprId, span: Span) -> ExprId {
+        self.arena.alloc(Expr::Binary { op, lhs, rhs }, Some(span))
+    }
+    fn call(&mut self, callee: Symbol, args: Vec<ExprId>, span: Span, callee_span: Span) -> ExprId {
+        let args = self.arena.alloc_list(args);
+        let call = Expr::Call {
+            callee,
+            args,
+            callee_span,
+        };
+        self.arena.alloc(call, Some(span))
+    }
+    fn array(&mut self, elements: Vec<ExprId>, span: Span) -> ExprId {
+        let elements = self.arena.alloc_list(elements);
+        self.arena.alloc(Expr::Array(elements), Some(span))
+    }
+    fn index(&mut self, array: ExprId, index: ExprId, span: Span) -> ExprId {
+        self.arena.alloc(Expr::Index { array, index }, Some(span))
+    }
+    fn closure(
+        &mut self,
+        proto: Arc<PrototypeAST>,
+        function_body: ExprId,
+        body: ExprId,
+        span: Span,
+    ) -> ExprId {
+        let closure = Expr::Closure {
+            proto,
+            function_body,
+            body,
+        };
+        self.arena.alloc(closure, Some(span))
+    }
+    fn error(&mut self, error: ParseError) -> ExprId {
+        let span = error.span();
+        self.arena.alloc(Expr::Error(Box::new(error)), span)
+    }
+    fn as_error<'a>(&'a self, expr: &'a ExprId) -> Option<&'a ParseError> {
+        match self.arena.get(*expr) {
+            Expr::Error(error) => Some(error),
+            _ => None,
+        }
+    }
+    fn span(&self, expr: &ExprId) -> Option<Span> {
+        self.arena.span(*expr)
+    }
+
+    fn function(&mut self, proto: Arc<PrototypeAST>, body: ExprId) -> ArenaFunction {
+        ArenaFunction { proto, body }
+    }
+    fn global(&mut self, name: Symbol, init: ExprId, span: Span) -> ArenaGlobal {
+        ArenaGlobal { name, init, span }
+    }
+
+    fn def_item(function: ArenaFunction) -> ArenaItem {
+        ArenaItem::Def(function)
+    }
+    fn extern_item(proto: Arc<PrototypeAST>) -> ArenaItem {
+        ArenaItem::Extern(proto)
+    }
+    fn global_item(global: ArenaGlobal) -> ArenaItem {
+        ArenaItem::Global(global)
+    }
+    fn expr_item(function: ArenaFunction) -> ArenaItem {
+        ArenaItem::Expr(function)
+    }
+}
+
+// 同 Program, 所有表达式在一个 AstArena 中
+#[derive(Debug, Clone, Default)]
+pub struct ArenaProgram {
+    arena: AstArena,
+    items: Vec<ArenaItem>,
+    interner: Interner,
+}
+impl ArenaProgram {
+    pub fn arena(&self) -> &AstArena {
+        &self.arena
+    }
+    pub fn items(&self) -> &[ArenaItem] {
+        &self.items
+    }
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+}
+
+// Same as `parse_str`, allocating the expressions into an arena. Errors
+// and recovery are those of `ASTParser::parse_program`; nodes of an item
+// that failed to parse are left in the arena unused.
+pub fn parse_str_arena(source: &str) -> Result<ArenaProgram, Vec<ParseError>> {
+    let mut parser = ASTParser::with_builder(Lexer::from_str(source), ArenaBuilder::new());
+    parser.update_token();
+    let mut items = Vec::new();
+    let mut errors = Vec::new();
+    while let Some((item, _)) = parser.parse_next_item() {
+        match item {
+            Ok(item) => items.push(item),
+            Err(error) => errors.push(error),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(ArenaProgram {
+        arena: parser.builder.into_arena(),
+        items,
+        interner: parser.interner,
+    })
+}
+
+#[cfg(test)]
+mod test_arena {
+    use super::*;
+    use crate::{
+        ArrayExprAST, BinaryExprAST, CallExprAST, ClosureExprAST, ExprAST, FunctionAST, GlobalAST,
+        IndexExprAST, NumberExprAST, Program, TopLevelItem, VariableExprAST, parse_str,
+    };
+
+    // 转换成 Arc 形式的 AST, 和 parse_str 的结果比较
+    fn to_arc(arena: &AstArena, id: ExprId) -> Arc<dyn ExprAST> {
+        let list = |list: ExprList| {
+            arena
+                .list(list)
+                .iter()
+                .map(|id| to_arc(arena, *id))
+                .collect()
+        };
+        match arena.get(id) {
+            Expr::Number(val) => Arc::new(NumberExprAST::new(*val)),
+            Expr::Variable(name) => Arc::new(VariableExprAST::new(name.clone())),
+            Expr::Binary { op, lhs, rhs } => Arc::new(BinaryExprAST::new(
+                *op,
+                to_arc(arena, *lhs),
+                to_arc(arena, *rhs),
+            )),
+            Expr::Call { callee, args, .. } => {
+                Arc::new(CallExprAST::new(callee.clone(), list(*args)))
+            }
+            Expr::Array(elements) => Arc::new(ArrayExprAST::new(list(*elements))),
+            Expr::Index { array, index } => Arc::new(IndexExprAST::new(
+                to_arc(arena, *array),
+                to_arc(arena, *index),
+            )),
+            Expr::Closure {
+                proto,
+                function_body,
+                body,
+            } => {
+                let function = FunctionAST::new(proto.clone(), to_arc(arena, *function_body));
+                Arc::new(ClosureExprAST::new(
+                    Arc::new(function),
+                    to_arc(arena, *body),
+                ))
+            }
+            Expr::Error(error) => panic!("unexpected error node: {}", error),
+        }
+    }
+
+    fn to_program(program: &ArenaProgram) -> Program {
+        let arena = program.arena();
+        let function = |function: &ArenaFunction| {
+            let body = to_arc(arena, function.body);
+            Arc::new(FunctionAST::new(function.proto.clone(), body))
+        };
+        let items = program
+            .items()
+            .iter()
+            .map(|item| match item {
+                ArenaItem::Def(def) => TopLevelItem::Def(function(def)),
+                ArenaItem::Extern(proto) => TopLevelItem::Extern(proto.clone()),
+                ArenaItem::Global(global) => TopLevelItem::Global(Arc::new(GlobalAST::new(
+                    global.name.clone(),
+                    to_arc(arena, global.init),
+                ))),
+                ArenaItem::Expr(expr) => TopLevelItem::Expr(function(expr)),
+            })
+            .collect();
+        Program::new(items)
+    }
+
+    #[test]
+    fn test_parse_str_arena() {
+        let source = "def f(x) x + g(1, [2][0]);\nglobal a = 1 < 2;\nextern g(x y);\n\
+                      f(a) + (def h(y) y * 2 in h(3))";
+        let program = parse_str_arena(source).unwrap();
+        assert_eq!(to_program(&program), parse_str(source).unwrap());
+        assert_eq!(program.items().len(), 4);
+
+        // 子节点先于父节点分配, 位置和 Arc 形式的 AST 相同
+        let ArenaItem::Def(f) = &program.items()[0] else {
+            panic!("expected a definition")
+        };
+        let arena = program.arena();
+        let Expr::Binary { op: '+', lhs, rhs } = arena[f.body] else {
+            panic!("expected x + ...")
+        };
+        assert_eq!(arena[lhs], Expr::Variable("x".into()));
+        assert!(lhs.0 < rhs.0 && rhs.0 < f.body.0);
+        let Expr::Call {
+            callee,
+            args,
+            callee_span,
+        } = &arena[rhs]
+        else {
+            panic!("expected a call")
+        };
+        assert_eq!(*callee, "g");
+        assert_eq!(callee_span, &Span::new(13, 14));
+        assert_eq!(arena.list(*args).len(), 2);
+        assert_eq!(arena.span(f.body), Some(Span::new(9, 25)));
+        assert_eq!(arena.span(rhs), Some(Span::new(13, 25)));
+        assert!(program.interner().get("g").is_some());
+    }
+
+    #[test]
+    fn test_parse_str_arena_errors() {
+        let source = "def f(x x; 1 +; ) 2";
+        assert_eq!(
+            parse_str_arena(source).unwrap_err(),
+            parse_str(source).unwrap_err()
+        );
+        assert!(parse_str_arena("").unwrap().arena().is_empty());
+    }
+}
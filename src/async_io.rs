@@ -0,0 +1,258 @@
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+use crate::stream::{Chunks, DEFAULT_MAX_PENDING, StreamParser};
+use crate::{LexError, Lexer, ParseError, Span, Token, TopLevelItem};
+
+// 读取当前可用的下一段输入交给 push, 输入结束时返回 false
+async fn fill<R: AsyncRead + Unpin>(
+    source: &mut BufReader<R>,
+    push: impl FnOnce(&[u8]),
+) -> io::Result<bool> {
+    let chunk = source.fill_buf().await?;
+    let len = chunk.len();
+    push(chunk);
+    source.consume(len);
+    Ok(len > 0)
+}
+
+// Async mirror of `Lexer`.
+// One lexer runs over the input as it arrives; a token is only returned
+// once the character after it has arrived (or input ended). Only a token
+// cut off by the end of the input read so far is lexed again. Errors are
+// the same `LexError`s the blocking lexer returns.
+pub struct AsyncLexer<R: AsyncRead + Unpin> {
+    source: BufReader<R>,
+    lexer: Lexer<Chunks>,
+    read: usize, // 已经读到的字节数
+}
+
+impl<R: AsyncRead + Unpin> AsyncLexer<R> {
+    pub fn new(source: R) -> Self {
+        AsyncLexer {
+            source: BufReader::new(source),
+            lexer: Lexer::new(Chunks::default()).unwrap(),
+            read: 0,
+        }
+    }
+
+    pub async fn get_token(&mut self) -> Result<Token, LexError> {
+        loop {
+            let checkpoint = self.lexer.begin();
+            let tok = self.lexer.update_token();
+            match self.lexer.end(checkpoint, false, DEFAULT_MAX_PENDING) {
+                Ok(true) => {
+                    return match self.lexer.error() {
+                        Some(error) => Err(error.clone()),
+                        None => Ok(tok),
+                    };
+                }
+                Ok(false) => {}
+                Err(span) => return Err(LexError::TokenTooLong(span, DEFAULT_MAX_PENDING)),
+            }
+            let (lexer, read) = (&mut self.lexer, &mut self.read);
+            let filled = fill(&mut self.source, |chunk| {
+                *read += chunk.len();
+                lexer.push(chunk);
+            });
+            match filled.await {
+                Ok(true) => {}
+                Ok(false) => self.lexer.finish(),
+                // 和 Lexer 一样: 读取失败后当作输入结束, 错误随 Eof 一起报告
+                Err(e) => {
+                    let span = Span::new(self.read, self.read);
+                    self.lexer.io_error = Some(LexError::Io(e.kind(), e.to_string(), span));
+                    self.lexer.finish();
+                }
+            }
+        }
+    }
+
+    pub fn cur_tok(&self) -> Token {
+        self.lexer.cur_tok
+    }
+    pub fn identifier_str(&self) -> &str {
+        self.lexer.identifier_text()
+    }
+    pub fn num_val(&self) -> Option<f64> {
+        self.lexer.num_val
+    }
+    pub fn error(&self) -> Option<&LexError> {
+        self.lexer.error()
+    }
+}
+
+// Async mirror of `ASTParser`, yielding one top-level item at a time.
+// An item is only returned once the token following it has arrived (or
+// input ended), so items are split exactly as the blocking parser does;
+// see `StreamParser`.
+pub struct AsyncParser<R: AsyncRead + Unpin> {
+    source: BufReader<R>,
+    parser: StreamParser,
+}
+
+impl<R: AsyncRead + Unpin> AsyncParser<R> {
+    pub fn new(source: R) -> Self {
+        AsyncParser {
+            source: BufReader::new(source),
+            parser: StreamParser::new(),
+        }
+    }
+
+    // 未完成的项最多缓存的字节数, 见 StreamParser::set_max_pending
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.parser.set_max_pending(max_pending);
+    }
+
+    pub async fn parse_next(&mut self) -> io::Result<Option<Result<TopLevelItem, ParseError>>> {
+        loop {
+            if let Some(item) = self.parser.next_item() {
+                return Ok(Some(item));
+            }
+            if self.parser.is_finished() {
+                return Ok(None);
+            }
+            let parser = &mut self.parser;
+            if !fill(&mut self.source, |chunk| parser.push(chunk)).await? {
+                self.parser.finish();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_async_io {
+    use super::*;
+    use crate::{ExprASTKind, Operator, Span};
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_async_lexer() {
+        let mut lexer1 = AsyncLexer::new("def foo(x)\n  x + 1.5\n".as_bytes());
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Def);
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Identifier);
+        assert_eq!(lexer1.identifier_str(), "foo");
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::LParen);
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Identifier);
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::RParen);
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Identifier);
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Op(Operator::Plus));
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Number);
+        assert_eq!(lexer1.num_val(), Some(1.5));
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Eof);
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Eof);
+    }
+
+    #[tokio::test]
+    async fn test_async_lexer_split_utf8() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut lexer1 = AsyncLexer::new(reader);
+
+        // "变量" 的第二个字符被截断
+        let bytes = "变量 ".as_bytes();
+        writer.write_all(&bytes[..4]).await.unwrap();
+        let write_rest = async {
+            tokio::task::yield_now().await;
+            writer.write_all(&bytes[4..]).await.unwrap();
+            drop(writer);
+        };
+        let (tok, _) = tokio::join!(lexer1.get_token(), write_rest);
+        assert_eq!(tok.unwrap(), Token::Identifier);
+        assert_eq!(lexer1.identifier_str(), "变量");
+        assert_eq!(lexer1.get_token().await.unwrap(), Token::Eof);
+    }
+
+    // 读完 data 之后读取失败
+    struct FailingReader {
+        data: &'static [u8],
+    }
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            if self.data.is_empty() {
+                return std::task::Poll::Ready(Err(io::Error::other("disk on fire")));
+            }
+            let len = buf.remaining().min(self.data.len());
+            buf.put_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_lexer_errors() {
+        // 和 Lexer::get_token 返回同样的 LexError
+        let mut lexer1 = AsyncLexer::new(FailingReader { data: b"1.2.3 x" });
+        let error = lexer1.get_token().await.unwrap_err();
+        assert_eq!(
+            error,
+            LexError::MalformedNumber("1.2.3".to_string(), Span::new(0, 5))
+        );
+        assert_eq!(lexer1.get_token().await, Ok(Token::Identifier));
+        // 读取失败在 Eof 处报告一次
+        let error = lexer1.get_token().await.unwrap_err();
+        assert_eq!(
+            error,
+            LexError::Io(
+                io::ErrorKind::Other,
+                "disk on fire".to_string(),
+                Span::new(7, 7)
+            )
+        );
+        assert_eq!(error.code(), "K0003");
+        assert_eq!(lexer1.get_token().await, Ok(Token::Eof));
+    }
+
+    #[tokio::test]
+    async fn test_parse_next_streaming() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut parser1 = AsyncParser::new(reader);
+
+        // 不需要换行, 读到下一个 token 的开头就可以确定上一项结束
+        writer.write_all(b"def f(x)\n x; 1 +").await.unwrap();
+        let item = parser1.parse_next().await.unwrap().unwrap();
+        assert!(matches!(item, Ok(TopLevelItem::Def(_))));
+
+        // `1 +` 需要后续输入才能结束
+        writer.write_all(b" 2;\nextern g()").await.unwrap();
+        drop(writer);
+        let item = parser1.parse_next().await.unwrap().unwrap();
+        let Ok(TopLevelItem::Expr(anon)) = item else {
+            panic!("expected a top-level expression")
+        };
+        assert!(matches!(anon.body().kind(), ExprASTKind::Binary));
+        let item = parser1.parse_next().await.unwrap().unwrap();
+        assert!(matches!(item, Ok(TopLevelItem::Extern(_))));
+        assert!(parser1.parse_next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_parse_next_errors() {
+        let mut parser1 = AsyncParser::new(") 1; ) 2;\ndef f(".as_bytes());
+        // 整个输入只用一个解析器: 位置从输入开头算起, 匿名函数接着编号
+        let mut results = Vec::new();
+        for _ in 0..4 {
+            results.push(match parser1.parse_next().await.unwrap().unwrap() {
+                Ok(TopLevelItem::Expr(anon)) => Ok(anon.proto().name().to_string()),
+                Ok(_) => panic!("expected a top-level expression"),
+                Err(error) => Err(error.span()),
+            });
+        }
+        assert_eq!(
+            results,
+            [
+                Err(Some(Span::new(0, 1))),
+                Ok("__anon_expr0".to_string()),
+                Err(Some(Span::new(5, 6))),
+                Ok("__anon_expr1".to_string()),
+            ]
+        );
+        let error = parser1.parse_next().await.unwrap().unwrap().unwrap_err();
+        assert!(error.is_incomplete());
+        assert!(parser1.parse_next().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::Write;
+
+use crate::format::print_prototype;
+use crate::{
+    ArrayExprAST, BinaryExprAST, CallExprAST, ClosureExprAST, ExprAST, ExprASTKind, FunctionAST,
+    GlobalAST, IndexExprAST, NumberExprAST, Program, PrototypeAST, VariableExprAST, binop_spelling,
+};
+
+// Renders the tree under `expr` as a Graphviz digraph, one box per node
+// labeled with its number, name or operator. Children are drawn left to
+// right in source order, so `dot -Tsvg` shows how an expression was
+// grouped by precedence.
+pub fn ast_to_dot(expr: &dyn ExprAST) -> String {
+    let mut graph = DotGraph::new();
+    graph.node(expr);
+    graph.finish()
+}
+
+// Same as `ast_to_dot` with every top-level item of `program` as a root.
+pub fn program_to_dot(program: &Program) -> String {
+    let mut graph = DotGraph::new();
+    for item in program.items() {
+        graph.node(item.as_ast().as_ref());
+    }
+    graph.finish()
+}
+
+struct DotGraph {
+    out: String,
+    nodes: usize,
+}
+impl DotGraph {
+    fn new() -> Self {
+        DotGraph {
+            out: String::from("digraph AST {\n    node [shape=box];\n"),
+            nodes: 0,
+        }
+    }
+
+    // 写出 expr 及其子树, 返回 expr 的节点编号
+    fn node(&mut self, expr: &dyn ExprAST) -> usize {
+        let id = self.nodes;
+        self.nodes += 1;
+        let (label, children) = describe(expr);
+        writeln!(self.out, "    n{} [label=\"{}\"];", id, escape(&label)).unwrap();
+        for child in children {
+            let child = self.node(child);
+            writeln!(self.out, "    n{} -> n{};", id, child).unwrap();
+        }
+        id
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push_str("}\n");
+        self.out
+    }
+}
+
+// 节点的标签和子节点
+fn describe(expr: &dyn ExprAST) -> (String, Vec<&dyn ExprAST>) {
+    match expr.kind() {
+        ExprASTKind::Number => {
+            let number = expr.as_any().downcast_ref::<NumberExprAST>().unwrap();
+            (number.val().to_string(), Vec::new())
+        }
+        ExprASTKind::Variable => {
+            let var = expr.as_any().downcast_ref::<VariableExprAST>().unwrap();
+            (var.name().to_string(), Vec::new())
+        }
+        ExprASTKind::Binary => {
+            let binary = expr.as_any().downcast_ref::<BinaryExprAST>().unwrap();
+            let children = vec![binary.lhs().as_ref(), binary.rhs().as_ref()];
+            (binop_spelling(binary.op()), children)
+        }
+        ExprASTKind::Call => {
+            let call = expr.as_any().downcast_ref::<CallExprAST>().unwrap();
+            let args = call.args().iter().map(|arg| arg.as_ref()).collect();
+            (format!("call {}", call.callee()), args)
+        }
+        ExprASTKind::Array => {
+            let array = expr.as_any().downcast_ref::<ArrayExprAST>().unwrap();
+            let elements = array.elements().iter().map(|e| e.as_ref()).collect();
+            ("[ ]".to_string(), elements)
+        }
+        ExprASTKind::Index => {
+            let index = expr.as_any().downcast_ref::<IndexExprAST>().unwrap();
+            let children = vec![index.array().as_ref(), index.index().as_ref()];
+            ("index".to_string(), children)
+        }
+        // 左边是局部函数, 右边是 in 之后的表达式
+        ExprASTKind::Closure => {
+            let closure = expr.as_any().downcast_ref::<ClosureExprAST>().unwrap();
+            let function: &dyn ExprAST = closure.function().as_ref();
+            ("in".to_string(), vec![function, closure.body().as_ref()])
+        }
+        ExprASTKind::Prototype => {
+            let proto = expr.as_any().downcast_ref::<PrototypeAST>().unwrap();
+            (format!("extern {}", print_prototype(proto)), Vec::new())
+        }
+        ExprASTKind::Global => {
+            let global = expr.as_any().downcast_ref::<GlobalAST>().unwrap();
+            let label = format!("global {}", global.name());
+            (label, vec![global.init().as_ref()])
+        }
+        ExprASTKind::Function => {
+            let function = expr.as_any().downcast_ref::<FunctionAST>().unwrap();
+            let label = format!("def {}", print_prototype(function.proto()));
+            (label, vec![function.body().as_ref()])
+        }
+        ExprASTKind::Error => ("error".to_string(), Vec::new()),
+        ExprASTKind::Empty => ("empty".to_string(), Vec::new()),
+    }
+}
+
+// DOT 字符串中的 " 和 \ 需要转义
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test_dot {
+    use super::*;
+    use crate::{TopLevelItem, parse_str};
+
+    #[test]
+    fn test_ast_to_dot() {
+        let program = parse_str("1 + x * f(2)").unwrap();
+        let TopLevelItem::Expr(function) = &program.items()[0] else {
+            panic!("expected a top-level expression");
+        };
+        assert_eq!(
+            ast_to_dot(function.body().as_ref()),
+            "digraph AST {\n    node [shape=box];\n    \
+             n0 [label=\"+\"];\n    \
+             n1 [label=\"1\"];\n    n0 -> n1;\n    \
+             n2 [label=\"*\"];\n    \
+             n3 [label=\"x\"];\n    n2 -> n3;\n    \
+             n4 [label=\"call f\"];\n    \
+             n5 [label=\"2\"];\n    n4 -> n5;\n    n2 -> n4;\n    n0 -> n2;\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_program_to_dot() {
+        let program = parse_str("def f(x: bool) x; extern g(a b); global h = [1][0]").unwrap();
+        let dot = program_to_dot(&program);
+        let labels: Vec<&str> = dot
+            .lines()
+            .filter_map(|line| line.split_once("[label=\"")?.1.strip_suffix("\"];"))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                "def f(x: bool)",
+                "x",
+                "extern g(a b)",
+                "global h",
+                "index",
+                "[ ]",
+                "1",
+                "0"
+            ]
+        );
+        assert_eq!(escape(r#"a"b\"#), r#"a\"b\\"#);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{BinaryExprAST, ExprAST, ExprASTKind, NumberExprAST};
+
+// Evaluates an expression made only of numbers and the built-in binary
+// operators. Returns None as soon as it meets anything whose value isn't
+// known before running the program: variables, calls, arrays, ...
+// The exception is `&&` and `||`, which short-circuit: once the left
+// operand decides the result the right one isn't looked at, so
+// `0 && f(1)` is Some(0.0) even though it contains a call.
+pub fn eval_const(expr: &dyn ExprAST) -> Option<f64> {
+    match expr.kind() {
+        ExprASTKind::Number => {
+            let number = expr.as_any().downcast_ref::<NumberExprAST>().unwrap();
+            Some(number.val())
+        }
+        ExprASTKind::Binary => {
+            let binary = expr.as_any().downcast_ref::<BinaryExprAST>().unwrap();
+            let lhs = eval_const(binary.lhs().as_ref())?;
+            // && 和 || 短路: 左边已经决定结果时不求右边的值
+            match (binary.op(), lhs != 0.0) {
+                ('&', false) => return Some(0.0),
+                ('|', true) => return Some(1.0),
+                _ => {}
+            }
+            let rhs = eval_const(binary.rhs().as_ref())?;
+            apply_binop(binary.op(), lhs, rhs)
+        }
+        _ => None,
+    }
+}
+
+// 比较和逻辑运算的结果是 1.0 或 0.0, 非 0 的值都算 true
+fn apply_binop(op: char, lhs: f64, rhs: f64) -> Option<f64> {
+    let truth = |b: bool| if b { 1.0 } else { 0.0 };
+    match op {
+        '|' => Some(truth(lhs != 0.0 || rhs != 0.0)),
+        '&' => Some(truth(lhs != 0.0 && rhs != 0.0)),
+        '<' => Some(truth(lhs < rhs)),
+        '+' => Some(lhs + rhs),
+        '-' => Some(lhs - rhs),
+        '*' => Some(lhs * rhs),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_eval {
+    use super::*;
+    use crate::{TopLevelItem, parse_str};
+
+    fn eval(source: &str) -> Option<f64> {
+        let program = parse_str(source).unwrap();
+        let TopLevelItem::Expr(function) = &program.items()[0] else {
+            panic!("expected a top-level expression");
+        };
+        eval_const(function.body().as_ref())
+    }
+
+    #[test]
+    fn test_eval_const() {
+        assert_eq!(eval("1 + 2 * 3"), Some(7.0));
+        assert_eq!(eval("(1 + 2) * 3 - 10"), Some(-1.0));
+        assert_eq!(eval("1 < 2"), Some(1.0));
+        assert_eq!(eval("2 < 1 + 0.5"), Some(0.0));
+        assert_eq!(eval("1 + x"), None);
+        assert_eq!(eval("f(1) * 2"), None);
+        assert_eq!(eval("[1, 2][0]"), None);
+
+        assert_eq!(eval("1 < 2 && 2 < 3"), Some(1.0));
+        assert_eq!(eval("0 || 2 * 0"), Some(0.0));
+        assert_eq!(eval("0.5 || 0"), Some(1.0));
+        // 右边不会被求值, 不是常量也没关系
+        assert_eq!(eval("0 && f(1)"), Some(0.0));
+        assert_eq!(eval("1 || x"), Some(1.0));
+        assert_eq!(eval("1 && x"), None);
+        assert_eq!(apply_binop('/', 1.0, 2.0), None);
+    }
+}
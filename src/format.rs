@@ -0,0 +1,426 @@
+use crate::{
+    ASTParser, ArrayExprAST, BinaryExprAST, CallExprAST, ClosureExprAST, ExprAST, ExprASTKind,
+    FunctionAST, GlobalAST, IndexExprAST, Lexer, LosslessToken, NumberExprAST, ParseError, Program,
+    PrototypeAST, Token, TopLevelItem, Trivia, TriviaKind, Type, VariableExprAST, binop_precedence,
+    binop_spelling, parse_str,
+};
+
+const INDENT: &str = "    ";
+
+// Pretty printer: prints an AST back as Kaleidoscope source.
+// Parentheses are only emitted where precedence requires them.
+pub fn print_item(item: &TopLevelItem) -> String {
+    match item {
+        TopLevelItem::Def(function) => print_function(function),
+        TopLevelItem::Extern(proto) => format!("extern {}", print_prototype(proto)),
+        TopLevelItem::Global(global) => print_global(global),
+        // 只打印匿名函数的函数体; 以 def 开头的函数体要加括号, 否则会读成定义
+        TopLevelItem::Expr(function) => {
+            let mut out = String::new();
+            write_operand(function.body().as_ref(), |_| false, &mut out);
+            out
+        }
+    }
+}
+
+// 每一项后面加 ';' 并换行, 不保留注释和空行
+pub fn print_program(program: &Program) -> String {
+    program
+        .items()
+        .iter()
+        .map(|item| print_item(item) + ";\n")
+        .collect()
+}
+
+pub fn print_global(global: &GlobalAST) -> String {
+    format!(
+        "global {} = {}",
+        global.name(),
+        print_expr(global.init().as_ref())
+    )
+}
+
+pub fn print_function(function: &FunctionAST) -> String {
+    format!(
+        "def {}\n{}{}",
+        print_prototype(function.proto()),
+        INDENT,
+        print_expr(function.body().as_ref())
+    )
+}
+
+// 只标注不是 double 的参数和返回值; 有标注时参数用 ", " 分隔
+pub fn print_prototype(proto: &PrototypeAST) -> String {
+    if !proto.is_typed() {
+        let mut args: Vec<&str> = proto.args().iter().map(|arg| arg.as_str()).collect();
+        if proto.is_varargs() {
+            args.push("...");
+        }
+        return format!("{}({})", proto.name(), args.join(" "));
+    }
+    let mut args: Vec<String> = proto
+        .args()
+        .iter()
+        .zip(proto.arg_types())
+        .map(|(arg, ty)| match ty {
+            Type::Double => arg.to_string(),
+            ty => format!("{}: {}", arg, ty),
+        })
+        .collect();
+    if proto.is_varargs() {
+        args.push("...".to_string());
+    }
+    let mut out = format!("{}({})", proto.name(), args.join(", "));
+    if proto.return_type() != Type::Double {
+        out.push_str(&format!(" -> {}", proto.return_type()));
+    }
+    out
+}
+
+pub fn print_expr(expr: &dyn ExprAST) -> String {
+    let mut out = String::new();
+    write_expr(expr, &mut out);
+    out
+}
+
+// A literal that parses back to `val`. Very large and very small
+// magnitudes use exponent notation instead of hundreds of digits, and
+// infinity, which only comes from literals that overflow, is printed as
+// such a literal.
+fn format_number(val: f64) -> String {
+    if val.is_infinite() {
+        "1e400".to_string()
+    } else if val != 0.0 && !(1e-5..1e16).contains(&val) {
+        format!("{:e}", val)
+    } else {
+        val.to_string()
+    }
+}
+
+fn write_expr(expr: &dyn ExprAST, out: &mut String) {
+    match expr.kind() {
+        ExprASTKind::Number => {
+            let number = expr.as_any().downcast_ref::<NumberExprAST>().unwrap();
+            out.push_str(&format_number(number.val()));
+        }
+        ExprASTKind::Variable => {
+            let var = expr.as_any().downcast_ref::<VariableExprAST>().unwrap();
+            out.push_str(var.name());
+        }
+        ExprASTKind::Binary => {
+            let binary = expr.as_any().downcast_ref::<BinaryExprAST>().unwrap();
+            let prec = binop_precedence(binary.op()).unwrap_or(-1);
+            // 运算符左结合: 右操作数优先级相同时也要加括号
+            write_operand(binary.lhs().as_ref(), |child| child < prec, out);
+            out.push(' ');
+            out.push_str(&binop_spelling(binary.op()));
+            out.push(' ');
+            write_operand(binary.rhs().as_ref(), |child| child <= prec, out);
+        }
+        ExprASTKind::Call => {
+            let call = expr.as_any().downcast_ref::<CallExprAST>().unwrap();
+            out.push_str(call.callee());
+            out.push('(');
+            for (i, arg) in call.args().iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expr(arg.as_ref(), out);
+            }
+            out.push(')');
+        }
+        ExprASTKind::Array => {
+            let array = expr.as_any().downcast_ref::<ArrayExprAST>().unwrap();
+            out.push('[');
+            for (i, element) in array.elements().iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expr(element.as_ref(), out);
+            }
+            out.push(']');
+        }
+        ExprASTKind::Index => {
+            let index = expr.as_any().downcast_ref::<IndexExprAST>().unwrap();
+            // 下标比所有二元运算符结合得都紧
+            write_operand(index.array().as_ref(), |_| true, out);
+            out.push('[');
+            write_expr(index.index().as_ref(), out);
+            out.push(']');
+        }
+        ExprASTKind::Closure => {
+            let closure = expr.as_any().downcast_ref::<ClosureExprAST>().unwrap();
+            let function = closure.function();
+            out.push_str("def ");
+            out.push_str(&print_prototype(function.proto()));
+            out.push(' ');
+            write_expr(function.body().as_ref(), out);
+            out.push_str(" in ");
+            write_expr(closure.body().as_ref(), out);
+        }
+        ExprASTKind::Prototype => {
+            let proto = expr.as_any().downcast_ref::<PrototypeAST>().unwrap();
+            out.push_str(&print_prototype(proto));
+        }
+        ExprASTKind::Global => {
+            let global = expr.as_any().downcast_ref::<GlobalAST>().unwrap();
+            out.push_str(&print_global(global));
+        }
+        ExprASTKind::Function => {
+            let function = expr.as_any().downcast_ref::<FunctionAST>().unwrap();
+            out.push_str(&print_function(function));
+        }
+        ExprASTKind::Error | ExprASTKind::Empty => {}
+    }
+}
+
+fn write_operand(expr: &dyn ExprAST, needs_parens: impl Fn(i32) -> bool, out: &mut String) {
+    let parens = match expr.as_any().downcast_ref::<BinaryExprAST>() {
+        Some(binary) => needs_parens(binop_precedence(binary.op()).unwrap_or(-1)),
+        // in 之后的表达式会一直延伸, 作为运算数时总是加括号
+        None => matches!(expr.kind(), ExprASTKind::Closure),
+    };
+    if parens {
+        out.push('(');
+    }
+    write_expr(expr, out);
+    if parens {
+        out.push(')');
+    }
+}
+
+// comments and layout around one formatted top-level item
+#[derive(Debug, Default)]
+struct Block {
+    text: String,
+    leading: Vec<String>,
+    trailing: Option<String>,
+    blank_before: bool,
+}
+
+// Reformats a whole source file: one item per line ending in ';', function
+// bodies indented, single spaces around operators. Comments are kept: a
+// comment at the end of an item's last line stays there, other comments
+// inside or before an item move onto their own lines above it. Blank lines
+// between items are collapsed to one.
+// Numbers are printed in their shortest form, so `1_000` becomes `1000`.
+pub fn format_source(source: &str) -> Result<String, Vec<ParseError>> {
+    let program = parse_str(source)?;
+    let starts = item_starts(source);
+    let tokens = lossless_tokens(source);
+
+    let mut blocks: Vec<Block> = program
+        .items()
+        .iter()
+        .map(|item| Block {
+            text: print_item(item),
+            ..Block::default()
+        })
+        .collect();
+    let mut tail = Vec::new();
+    let mut tail_blank = false;
+
+    // token 属于在它之前开始的最后一个顶层项, 项后的 ';' 也算在内
+    let owner = |token: &LosslessToken| {
+        starts
+            .partition_point(|&start| start <= token.span.start)
+            .saturating_sub(1)
+    };
+    // 每个顶层项的最后一个 token
+    let mut last_token = vec![0; blocks.len()];
+    for (i, token) in tokens.iter().enumerate() {
+        if token.tok != Token::Eof && !blocks.is_empty() {
+            last_token[owner(token)] = i;
+        }
+    }
+
+    for (i, token) in tokens.iter().enumerate() {
+        if token.tok == Token::Eof || blocks.is_empty() {
+            tail_blank = has_blank_line(token);
+            tail.extend(comments(&token.leading));
+            continue;
+        }
+        let item = owner(token);
+        let block = &mut blocks[item];
+        if token.span.start == starts[item] {
+            block.blank_before = has_blank_line(token);
+        }
+        block.leading.extend(comments(&token.leading));
+        let trailing = comments(&token.trailing);
+        if i == last_token[item] {
+            block.trailing = trailing.into_iter().next();
+        } else {
+            block.leading.extend(trailing);
+        }
+    }
+
+    let mut out = String::new();
+    for (i, block) in blocks.iter().enumerate() {
+        if i > 0 && block.blank_before {
+            out.push('\n');
+        }
+        for comment in &block.leading {
+            out.push_str(comment);
+            out.push('\n');
+        }
+        out.push_str(&block.text);
+        out.push(';');
+        if let Some(comment) = &block.trailing {
+            out.push(' ');
+            out.push_str(comment);
+        }
+        out.push('\n');
+    }
+    if !tail.is_empty() && !blocks.is_empty() && tail_blank {
+        out.push('\n');
+    }
+    for comment in &tail {
+        out.push_str(comment);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+fn comments(trivia: &[Trivia]) -> Vec<String> {
+    trivia
+        .iter()
+        .filter(|trivia| trivia.kind == TriviaKind::Comment)
+        .map(|trivia| trivia.text.clone())
+        .collect()
+}
+
+// 第一段前导空白中有空行
+fn has_blank_line(token: &LosslessToken) -> bool {
+    match token.leading.first() {
+        Some(trivia) if trivia.kind == TriviaKind::Whitespace => {
+            trivia.text.matches('\n').count() >= 2
+        }
+        _ => false,
+    }
+}
+
+// 每个顶层项第一个 token 的起始偏移
+fn item_starts(source: &str) -> Vec<usize> {
+    let mut parser = ASTParser::new(Lexer::from_str(source));
+    parser.update_token();
+    let mut starts = Vec::new();
+    while let Some((Ok(_), span)) = parser.parse_top_level_with_span() {
+        starts.push(span.start);
+    }
+    starts
+}
+
+fn lossless_tokens(source: &str) -> Vec<LosslessToken> {
+    let mut lexer = Lexer::from_str(source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.get_lossless_token();
+        let eof = token.tok == Token::Eof;
+        tokens.push(token);
+        if eof {
+            return tokens;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_format {
+    use super::*;
+
+    #[test]
+    fn test_print_expr() {
+        let program = parse_str("(a + b) * (c - (d - e)) < f(1.5, (x), 2e3); a - b - c").unwrap();
+        let printed: Vec<String> = program.items().iter().map(print_item).collect();
+        assert_eq!(
+            printed,
+            ["(a + b) * (c - (d - e)) < f(1.5, x, 2000)", "a - b - c"]
+        );
+
+        let program = parse_str("(a || b) && c<d || e; a || (b || c)").unwrap();
+        let printed: Vec<String> = program.items().iter().map(print_item).collect();
+        assert_eq!(printed, ["(a || b) && c < d || e", "a || (b || c)"]);
+    }
+
+    #[test]
+    fn test_format_source() {
+        let source = "# helpers\ndef  add(x y) x+y;  # sum\n\n\nextern sin(a);;add(1,sin( 2 ))\n";
+        let expected =
+            "# helpers\ndef add(x y)\n    x + y; # sum\n\nextern sin(a);\nadd(1, sin(2));\n";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(formatted, expected);
+        // 格式化结果不再改变
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_comments() {
+        let source = "def f(x) # doc\n  x # inner\n  * 2;\n\n# end\n";
+        let expected = "# doc\n# inner\ndef f(x)\n    x * 2;\n\n# end\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(
+            format_source("# only a comment").unwrap(),
+            "# only a comment\n"
+        );
+        assert_eq!(format_source("").unwrap(), "");
+    }
+
+    #[test]
+    fn test_format_types() {
+        let source = "def f(x:double,flag : bool)->bool flag;extern g(a b:bool)";
+        let expected = "def f(x, flag: bool) -> bool\n    flag;\nextern g(a, b: bool);\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+
+        let source = "extern printf(fmt,...);extern any(...)->bool";
+        let expected = "extern printf(fmt ...);\nextern any(...) -> bool;\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_arrays() {
+        let source = "def f(a:array) a[0]+[1,a[1]*2][1];(1+2)[0];[ ]";
+        let expected = "def f(a: array)\n    a[0] + [1, a[1] * 2][1];\n(1 + 2)[0];\n[];\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_globals() {
+        let source = "global  pi=3.14159;def area(r) pi*r*r";
+        let expected = "global pi = 3.14159;\ndef area(r)\n    pi * r * r;\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_closures() {
+        let source = "def f(x) 1+def g(y)x*y in g(2)*(def h() x in h())[0]";
+        let expected = "def f(x)\n    1 + (def g(y) x * y in g(2) * (def h() x in h())[0]);\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+
+        // 顶层表达式以 def 开头时要加括号
+        let program = parse_str("(def g(y) y in g(1)); extern h(a, ...)").unwrap();
+        assert_eq!(
+            print_program(&program),
+            "(def g(y) y in g(1));\nextern h(a ...);\n"
+        );
+    }
+
+    #[test]
+    fn test_format_numbers() {
+        let source = "1e400; 1e300 * 1e10; 1.5e-7 + 0.00001; 12345678901234567890; 1_000";
+        let expected =
+            "1e400;\n1e300 * 10000000000;\n1.5e-7 + 0.00001;\n1.2345678901234567e19;\n1000;\n";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(parse_str(&formatted).unwrap(), parse_str(source).unwrap());
+    }
+
+    #[test]
+    fn test_format_errors() {
+        assert!(format_source("def f(x x +").is_err());
+        assert!(format_source("1 2").is_err());
+    }
+}
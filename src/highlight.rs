@@ -0,0 +1,339 @@
+use crate::{Keyword, Lexer, Span, Token, TriviaKind};
+
+// What a piece of source is, for syntax highlighting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenClass {
+    Keyword,
+    Function,  // 原型中的函数名, 以及被调用的函数名
+    Parameter, // 原型中的参数, 以及函数体中对参数的引用
+    Type,      // 类型标注中的类型名
+    Variable,  // 其它标识符
+    Number,
+    Operator,
+    Punctuation, // ( ) [ ] , ; : -> = ...
+    Comment,
+}
+
+// Splits `source` into highlighted pieces in source order. Whitespace is
+// left out. Works on incomplete or invalid code as well: classification
+// only looks at the surrounding tokens and never needs a successful parse.
+pub fn classify_tokens(source: &str) -> Vec<(Span, TokenClass)> {
+    let mut lexer = Lexer::from_str(source);
+    let mut tokens = Vec::new();
+    let mut comments = Vec::new();
+    loop {
+        let token = lexer.get_lossless_token();
+        // 记下注释出现在哪个 token 之前
+        let before = tokens.len();
+        for (trivia, before) in token
+            .leading
+            .iter()
+            .map(|trivia| (trivia, before))
+            .chain(token.trailing.iter().map(|trivia| (trivia, before + 1)))
+        {
+            if trivia.kind == TriviaKind::Comment {
+                comments.push((before, trivia.span));
+            }
+        }
+        if token.tok == Token::Eof {
+            break;
+        }
+        tokens.push((token.tok, token.text, token.span));
+    }
+
+    let mut classes = Vec::new();
+    let mut proto = Prototype::None;
+    let mut params = Vec::new(); // 当前可见的参数, 包括外层函数的
+    let mut scopes = Vec::new(); // 每个局部函数开始时 params 的长度
+    for (i, (tok, text, span)) in tokens.iter().enumerate() {
+        let next = tokens.get(i + 1).map(|(tok, _, _)| *tok);
+        let prev = i.checked_sub(1).map(|prev| tokens[prev].0);
+        let prev_class = classes.last().map(|(_, _, class)| *class);
+        let class = match tok {
+            Token::Def | Token::Extern => {
+                // 新的顶层项开始, 前一个函数的参数不再可见; 局部函数还能看到外层的参数
+                if matches!(prev, None | Some(Token::Semicolon)) {
+                    params.clear();
+                    scopes.clear();
+                } else {
+                    scopes.push(params.len());
+                }
+                proto = Prototype::Name;
+                TokenClass::Keyword
+            }
+            Token::Keyword(keyword) if is_keyword(*keyword, prev, prev_class, next, proto) => {
+                // in 之后是局部函数的作用域, 它的参数不再可见
+                if *keyword == Keyword::In
+                    && let Some(len) = scopes.pop()
+                {
+                    params.truncate(len);
+                }
+                TokenClass::Keyword
+            }
+            // 其它位置的上下文关键字和标识符一样
+            Token::Identifier | Token::Keyword(_) => match proto {
+                // x: double 或 ) -> bool
+                Prototype::Args if prev == Some(Token::Colon) => TokenClass::Type,
+                Prototype::Return if prev == Some(Token::Arrow) => {
+                    proto = Prototype::None;
+                    TokenClass::Type
+                }
+                Prototype::Name => {
+                    proto = Prototype::Args;
+                    TokenClass::Function
+                }
+                Prototype::Args => {
+                    params.push(text.as_str());
+                    TokenClass::Parameter
+                }
+                Prototype::None | Prototype::Return if next == Some(Token::LParen) => {
+                    proto = Prototype::None;
+                    TokenClass::Function
+                }
+                Prototype::None | Prototype::Return if params.contains(&text.as_str()) => {
+                    proto = Prototype::None;
+                    TokenClass::Parameter
+                }
+                Prototype::None | Prototype::Return => {
+                    proto = Prototype::None;
+                    TokenClass::Variable
+                }
+            },
+            Token::Number => {
+                proto = Prototype::None;
+                TokenClass::Number
+            }
+            Token::None | Token::Eof | Token::Comment => continue,
+            // 符号
+            tok => {
+                match tok {
+                    Token::RParen if proto == Prototype::Args => proto = Prototype::Return,
+                    Token::Arrow if proto == Prototype::Return => {}
+                    _ if proto == Prototype::Return => proto = Prototype::None,
+                    Token::Semicolon => {
+                        params.clear();
+                        scopes.clear();
+                        proto = Prototype::None;
+                    }
+                    _ => {}
+                }
+                match tok {
+                    Token::LParen
+                    | Token::RParen
+                    | Token::LBracket
+                    | Token::RBracket
+                    | Token::Comma
+                    | Token::Semicolon
+                    | Token::Colon
+                    | Token::Arrow
+                    | Token::Equals
+                    | Token::Ellipsis => TokenClass::Punctuation,
+                    _ => TokenClass::Operator,
+                }
+            }
+        };
+        classes.push((i, *span, class));
+    }
+
+    // 按位置合并注释和 token
+    let mut result = Vec::with_capacity(classes.len() + comments.len());
+    let mut comments = comments.into_iter().peekable();
+    for (i, span, class) in classes {
+        while let Some((_, comment)) = comments.next_if(|(before, _)| *before <= i) {
+            result.push((comment, TokenClass::Comment));
+        }
+        result.push((span, class));
+    }
+    result.extend(comments.map(|(_, comment)| (comment, TokenClass::Comment)));
+    result
+}
+
+// 上下文关键字在这里是关键字还是名字, 和 parser 的规则一致
+fn is_keyword(
+    keyword: Keyword,
+    prev: Option<Token>,
+    prev_class: Option<TokenClass>,
+    next: Option<Token>,
+    proto: Prototype,
+) -> bool {
+    match keyword {
+        // 顶层项的开头, 后面是名字
+        Keyword::Import | Keyword::Global => {
+            matches!(prev, None | Some(Token::Semicolon)) && next.is_some_and(Token::is_name)
+        }
+        // 闭包的函数体之后, 也就是紧跟在一个表达式的末尾
+        Keyword::In => {
+            let after_expression = match prev {
+                Some(Token::Number | Token::RParen | Token::RBracket) => true,
+                Some(tok) => tok.is_name() && prev_class != Some(TokenClass::Keyword),
+                None => false,
+            };
+            after_expression && !matches!(proto, Prototype::Name | Prototype::Args)
+        }
+    }
+}
+
+// 在原型中的位置
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Prototype {
+    None,
+    Name,   // 下一个标识符是函数名
+    Args,   // 在参数列表中
+    Return, // 参数列表之后, 可能有返回值类型
+}
+
+#[cfg(test)]
+mod test_highlight {
+    use super::*;
+
+    fn classes(source: &str) -> Vec<(&str, TokenClass)> {
+        classify_tokens(source)
+            .into_iter()
+            .map(|(span, class)| (&source[span.start..span.end], class))
+            .collect()
+    }
+
+    #[test]
+    fn test_classify_tokens() {
+        use TokenClass::*;
+        let source = "# square\ndef sq(x) x * x; # done\nextern sin(a);\nsq(sin(x) + 1.5)";
+        assert_eq!(
+            classes(source),
+            [
+                ("# square", Comment),
+                ("def", Keyword),
+                ("sq", Function),
+                ("(", Punctuation),
+                ("x", Parameter),
+                (")", Punctuation),
+                ("x", Parameter),
+                ("*", Operator),
+                ("x", Parameter),
+                (";", Punctuation),
+                ("# done", Comment),
+                ("extern", Keyword),
+                ("sin", Function),
+                ("(", Punctuation),
+                ("a", Parameter),
+                (")", Punctuation),
+                (";", Punctuation),
+                ("sq", Function),
+                ("(", Punctuation),
+                ("sin", Function),
+                ("(", Punctuation),
+                // 函数之外的 x 不是参数
+                ("x", Variable),
+                (")", Punctuation),
+                ("+", Operator),
+                ("1.5", Number),
+                (")", Punctuation),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_classify_types() {
+        use TokenClass::*;
+        let classes = classes("def f(x: bool) -> bool x; extern g() - 1");
+        let types: Vec<_> = classes.iter().filter(|(_, class)| *class == Type).collect();
+        assert_eq!(types, [&("bool", Type), &("bool", Type)]);
+        assert_eq!(classes[9], ("x", Parameter));
+        assert_eq!(classes[4], (":", Punctuation));
+        assert_eq!(classes[7], ("->", Punctuation));
+        assert_eq!(classes[classes.len() - 1], ("1", Number));
+    }
+
+    #[test]
+    fn test_classify_closures() {
+        use TokenClass::*;
+        let classes = classes("def f(x) def g(y) x * y in g(y); x");
+        let names: Vec<_> = classes
+            .iter()
+            .filter(|(text, _)| text.chars().all(char::is_alphabetic))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                &("def", Keyword),
+                &("f", Function),
+                &("x", Parameter),
+                &("def", Keyword),
+                &("g", Function),
+                &("y", Parameter),
+                &("x", Parameter),
+                &("y", Parameter),
+                &("in", Keyword),
+                &("g", Function),
+                // g 的参数只在 in 之前可见
+                &("y", Variable),
+                &("x", Variable),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_classify_invalid_source() {
+        use TokenClass::*;
+        // 不完整的代码也能分类, 注释在最后
+        assert_eq!(
+            classes("def f(x y\n  x + 1..2 # tail"),
+            [
+                ("def", Keyword),
+                ("f", Function),
+                ("(", Punctuation),
+                ("x", Parameter),
+                ("y", Parameter),
+                ("x", Parameter),
+                ("+", Operator),
+                ("1..2", Number),
+                ("# tail", Comment),
+            ]
+        );
+        assert_eq!(classes(""), []);
+    }
+
+    #[test]
+    fn test_classify_contextual_keywords() {
+        use TokenClass::*;
+        let classes = classes("global in = 1; def f(global) def in(y) y in in(global); import m");
+        assert_eq!(classes[2], ("=", TokenClass::Punctuation));
+        let names: Vec<_> = classes
+            .iter()
+            .filter(|(text, _)| text.chars().all(char::is_alphabetic))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                &("global", Keyword),
+                &("in", Variable),
+                &("def", Keyword),
+                &("f", Function),
+                &("global", Parameter),
+                &("def", Keyword),
+                &("in", Function),
+                &("y", Parameter),
+                &("y", Parameter),
+                &("in", Keyword),
+                &("in", Function),
+                &("global", Parameter),
+                &("import", Keyword),
+                &("m", Variable),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_classify_logical_operators() {
+        use TokenClass::*;
+        assert_eq!(
+            classes("a && b || c"),
+            [
+                ("a", Variable),
+                ("&&", Operator),
+                ("b", Variable),
+                ("||", Operator),
+                ("c", Variable),
+            ]
+        );
+    }
+}
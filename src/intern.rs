@@ -0,0 +1,141 @@
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+// An interned identifier. AST nodes store symbols instead of `String`s;
+// symbols made by the same interner share one allocation per distinct
+// name, and comparing two of them is a pointer comparison first.
+//
+// A symbol holds the shared name rather than a u32 id into a table. An id
+// could only be read back through the interner that made it, and that
+// would have to be passed to everything that prints or compares names
+// (sema, format, lsp, dot); a process-wide table instead would never free
+// its names. Cloning a symbol only bumps a reference count, and the AST
+// stays Send + Sync for `parse_files`.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(Arc<str>);
+impl Symbol {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+// 不经过 interner 的 symbol, 用于手工构造 AST
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol(name.into())
+    }
+}
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol(name.into())
+    }
+}
+// 以 Symbol 为键的表可以直接用 &str 查找
+impl Borrow<str> for Symbol {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+// Names seen by one parser. Every `ASTParser` owns an interner and hands
+// a copy to the `Program` it builds; the names are freed when the last
+// parser, program or AST node using them is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    names: HashSet<Symbol>,
+}
+impl Interner {
+    pub fn new() -> Self {
+        Interner::default()
+    }
+
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.names.get(name) {
+            return symbol.clone();
+        }
+        let symbol = Symbol::from(name);
+        self.names.insert(symbol.clone());
+        symbol
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.names.get(name)
+    }
+
+    // 加入 other 中的名字, 例如把多个文件的 interner 合并
+    pub fn extend(&mut self, other: &Interner) {
+        self.names.extend(other.names.iter().cloned());
+    }
+
+    // 已经驻留的名字个数
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test_intern {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let mut interner = Interner::new();
+        let x = interner.intern("x");
+        let y = interner.intern("y");
+        assert_ne!(x, y);
+        assert_eq!(interner.intern("x"), x);
+        assert_eq!(interner.len(), 2);
+        assert_eq!(x.as_str(), "x");
+        assert_eq!(x, "x");
+        assert_eq!(format!("{} {:?}", x, y), "x \"y\"");
+        assert_eq!(interner.get("x"), Some(&x));
+        assert_eq!(interner.get("never_used"), None);
+        // 手工构造的 symbol 和驻留的按名字比较
+        assert_eq!(Symbol::from(String::from("y")), y);
+
+        // 不再使用的名字会被释放
+        let weak = Arc::downgrade(&x.0);
+        drop((x, interner));
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_parser_shares_symbols() {
+        let program = crate::parse_str("def f(x) x + f(x)").unwrap();
+        let crate::TopLevelItem::Def(f) = &program.items()[0] else {
+            panic!("expected a definition");
+        };
+        let x = &f.proto().args()[0];
+        let interned = program.interner().get("x").unwrap();
+        assert!(Arc::ptr_eq(&x.0, &interned.0));
+        let body = f.body().as_any().downcast_ref::<crate::BinaryExprAST>();
+        let lhs = body.unwrap().lhs().as_any();
+        let var = lhs.downcast_ref::<crate::VariableExprAST>().unwrap();
+        assert!(Arc::ptr_eq(&var.symbol().0, &x.0));
+        assert_eq!(program.interner().len(), 2);
+    }
+}
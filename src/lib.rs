@@ -1,4 +1,23 @@
-#[derive(Copy, Clone, Debug,PartialEq)]
+#[cfg(feature = "proptest")]
+pub mod arbitrary;
+pub mod arena;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod dot;
+pub mod eval;
+pub mod format;
+pub mod highlight;
+pub mod intern;
+pub mod loader;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod report;
+pub mod runtime;
+pub mod sema;
+pub mod stream;
+pub mod trace;
+
+#[derive(Copy, Clone, Debug,PartialEq, Hash)]
 pub enum Token {
     None,
     Eof,
@@ -6,8 +25,255 @@ pub enum Token {
     Extern,
     Identifier,
     Number,
-    Char(char),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Semicolon,
+    Colon,
+    Equals,
+    Arrow,    // -> before a return type
+    Ellipsis, // ... in a varargs extern
+    Op(Operator),
+    Unknown(char), // a character that is not part of the language
     Comment,
+    Keyword(Keyword), // import, global, in; names everywhere else
+}
+impl Token {
+    // 可以作为名字的 token: 标识符和上下文关键字
+    pub fn is_name(self) -> bool {
+        matches!(self, Token::Identifier | Token::Keyword(_))
+    }
+    // 符号的原文, 例如 "(" 和 "&&"; 其它 token 返回 None
+    pub fn spelling(self) -> Option<&'static str> {
+        PUNCTUATION
+            .iter()
+            .find(|(_, tok)| *tok == self)
+            .map(|(text, _)| *text)
+    }
+}
+
+// binary operators, loosest first
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Operator {
+    Or,
+    And,
+    Less,
+    Plus,
+    Minus,
+    Times,
+}
+impl Operator {
+    pub const ALL: [Operator; 6] = [
+        Operator::Or,
+        Operator::And,
+        Operator::Less,
+        Operator::Plus,
+        Operator::Minus,
+        Operator::Times,
+    ];
+    // 数值越大结合越紧
+    pub fn precedence(self) -> i32 {
+        match self {
+            Operator::Or => 4,
+            Operator::And => 6,
+            Operator::Less => 10,
+            Operator::Plus | Operator::Minus => 20,
+            Operator::Times => 40,
+        }
+    }
+    // AST 中的写法: && 和 || 记作 '&' 和 '|'
+    pub fn as_char(self) -> char {
+        match self {
+            Operator::Or => '|',
+            Operator::And => '&',
+            Operator::Less => '<',
+            Operator::Plus => '+',
+            Operator::Minus => '-',
+            Operator::Times => '*',
+        }
+    }
+    pub fn from_char(op: char) -> Option<Operator> {
+        Operator::ALL.into_iter().find(|binop| binop.as_char() == op)
+    }
+    pub fn spelling(self) -> &'static str {
+        Token::Op(self).spelling().unwrap()
+    }
+}
+
+// every symbol of the language and its token
+pub const PUNCTUATION: [(&str, Token); 16] = [
+    ("(", Token::LParen),
+    (")", Token::RParen),
+    ("[", Token::LBracket),
+    ("]", Token::RBracket),
+    (",", Token::Comma),
+    (";", Token::Semicolon),
+    (":", Token::Colon),
+    ("=", Token::Equals),
+    ("->", Token::Arrow),
+    ("...", Token::Ellipsis),
+    ("||", Token::Op(Operator::Or)),
+    ("&&", Token::Op(Operator::And)),
+    ("<", Token::Op(Operator::Less)),
+    ("+", Token::Op(Operator::Plus)),
+    ("-", Token::Op(Operator::Minus)),
+    ("*", Token::Op(Operator::Times)),
+];
+
+// 符号 text 的 token, 不是语言中的符号时返回 None
+pub fn punctuation_token(text: &str) -> Option<Token> {
+    PUNCTUATION
+        .iter()
+        .find(|(spelling, _)| *spelling == text)
+        .map(|(_, tok)| *tok)
+}
+
+// Contextual keywords only mean something in one position and are
+// ordinary names everywhere else, so `def f(in) in` still works:
+//   import  at the start of a top-level item, followed by a name
+//   global  at the start of a top-level item, followed by a name
+//   in      after the body of a closure
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Keyword {
+    Import,
+    Global,
+    In,
+}
+impl Keyword {
+    pub fn spelling(self) -> &'static str {
+        match self {
+            Keyword::Import => "import",
+            Keyword::Global => "global",
+            Keyword::In => "in",
+        }
+    }
+}
+
+// the standard keywords, what `KeywordTable::new` starts with.
+// def 和 extern 是保留字, 其它的是上下文关键字
+pub const KEYWORDS: [(&str, Token); 5] = [
+    ("def", Token::Def),
+    ("extern", Token::Extern),
+    ("import", Token::Keyword(Keyword::Import)),
+    ("global", Token::Keyword(Keyword::Global)),
+    ("in", Token::Keyword(Keyword::In)),
+];
+
+// keyword -> token mapping used by the lexer
+#[derive(Debug, Clone)]
+pub struct KeywordTable {
+    keywords: HashMap<String, Token>,
+}
+impl Default for KeywordTable {
+    fn default() -> Self {
+        let mut table = KeywordTable::empty();
+        for (word, tok) in KEYWORDS {
+            table.insert(word, tok);
+        }
+        table
+    }
+}
+impl KeywordTable {
+    // the standard keywords, see KEYWORDS
+    pub fn new() -> Self {
+        KeywordTable::default()
+    }
+    pub fn empty() -> Self {
+        KeywordTable {
+            keywords: HashMap::new(),
+        }
+    }
+    pub fn insert(&mut self, word: &str, tok: Token) {
+        self.keywords.insert(word.to_string(), tok);
+    }
+    pub fn remove(&mut self, word: &str) -> Option<Token> {
+        self.keywords.remove(word)
+    }
+    pub fn get(&self, word: &str) -> Option<Token> {
+        self.keywords.get(word).copied()
+    }
+    // 所有 (关键字, token), 顺序不固定
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Token)> {
+        self.keywords.iter().map(|(word, tok)| (word.as_str(), *tok))
+    }
+}
+
+// What the lexer does with a character when it is about to start a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CharClass {
+    Whitespace,      // 跳过
+    CommentStart,    // '#', 注释到行尾
+    IdentifierStart, // 字母, 开始标识符或关键字
+    NumberStart,     // 数字或 '.', 开始数字字面量
+    Symbol,          // 其它字符, 成为符号或者 Token::Unknown
+}
+
+pub fn classify_char(c: char) -> CharClass {
+    match c {
+        c if c.is_whitespace() => CharClass::Whitespace,
+        '#' => CharClass::CommentStart,
+        c if c.is_alphabetic() => CharClass::IdentifierStart,
+        c if c.is_ascii_digit() || c == '.' => CharClass::NumberStart,
+        _ => CharClass::Symbol,
+    }
+}
+
+// 标识符中第一个字符之后的字符
+pub fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+// 数字字面量中的字符; prev 是字面量中的前一个字符, 用于判断指数的符号
+pub fn is_number_char(c: char, prev: Option<char>) -> bool {
+    c.is_alphanumeric()
+        || c == '.'
+        || c == '_'
+        || (matches!(c, '+' | '-') && matches!(prev, Some('e' | 'E')))
+}
+
+// localized spellings for keywords and operators, e.g. "定义" for "def".
+// An alias lexes to the same token as its canonical spelling.
+#[derive(Debug, Clone, Default)]
+pub struct LanguageConfig {
+    aliases: HashMap<String, String>, // alias -> canonical spelling
+}
+impl LanguageConfig {
+    pub fn new() -> Self {
+        LanguageConfig::default()
+    }
+    // 中文关键字和运算符
+    pub fn chinese() -> Self {
+        let mut config = LanguageConfig::new();
+        config.alias("定义", "def");
+        config.alias("外部", "extern");
+        config.alias("导入", "import");
+        config.alias("全局", "global");
+        config.alias("加", "+");
+        config.alias("减", "-");
+        config.alias("乘", "*");
+        config.alias("小于", "<");
+        config
+    }
+    pub fn alias(&mut self, alias: &str, canonical: &str) {
+        self.aliases.insert(alias.to_string(), canonical.to_string());
+    }
+    pub fn canonical(&self, alias: &str) -> Option<&str> {
+        self.aliases.get(alias).map(String::as_str)
+    }
+
+    // 把别名登记到关键字表中.
+    // 规范写法是关键字或符号时取对应的 token,
+    // 其他的别名无法对应到 token, 会被忽略
+    pub fn apply(&self, keywords: &mut KeywordTable) {
+        for (alias, canonical) in &self.aliases {
+            let tok = punctuation_token(canonical).or_else(|| keywords.get(canonical));
+            if let Some(tok) = tok {
+                keywords.insert(alias, tok);
+            }
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -24,114 +290,758 @@ impl CharState {
             _ => false,
         }
     }
+    pub fn is_whitespace(&self) -> bool {
+        match self {
+            CharState::Char(c) => c.is_whitespace(),
+            _ => false,
+        }
+    }
 }
 
 use core::str;
 use std::{
     char,
-    io::{self, Read},
-    rc::Rc,
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+    sync::Arc,
 };
+// 源码中的字节范围 [start, end)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+impl Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub enum LexError {
+    MalformedNumber(String, Span),
+    TokenTooLong(Span, usize), // token 的范围和长度上限
+    Io(io::ErrorKind, String, Span), // 读取失败时的错误信息和位置
+    InvalidUtf8(Span),
+}
+impl Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::MalformedNumber(text, span) => {
+                write!(f, "malformed number literal `{}` at {}", text, span)
+            }
+            LexError::TokenTooLong(span, limit) => {
+                write!(f, "token at {} is longer than the limit of {} bytes", span, limit)
+            }
+            LexError::Io(_, message, span) => {
+                write!(f, "failed to read input at {}: {}", span.start, message)
+            }
+            LexError::InvalidUtf8(span) => write!(f, "invalid UTF-8 at {}", span),
+        }
+    }
+}
+impl LexError {
+    // 稳定的错误码, 新的错误只能追加新的编号
+    pub fn code(&self) -> &'static str {
+        match self {
+            LexError::MalformedNumber(..) => "K0001",
+            LexError::TokenTooLong(..) => "K0002",
+            LexError::Io(..) => "K0003",
+            LexError::InvalidUtf8(_) => "K0004",
+        }
+    }
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::MalformedNumber(_, span) => *span,
+            LexError::TokenTooLong(span, _) => *span,
+            LexError::Io(_, _, span) => *span,
+            LexError::InvalidUtf8(span) => *span,
+        }
+    }
+    fn map_span(self, f: impl Fn(Span) -> Span) -> LexError {
+        match self {
+            LexError::MalformedNumber(text, span) => LexError::MalformedNumber(text, f(span)),
+            LexError::TokenTooLong(span, limit) => LexError::TokenTooLong(f(span), limit),
+            LexError::Io(kind, message, span) => LexError::Io(kind, message, f(span)),
+            LexError::InvalidUtf8(span) => LexError::InvalidUtf8(f(span)),
+        }
+    }
+}
+// 还没有读取任何输入时的错误, 例如打开文件失败
+impl From<io::Error> for LexError {
+    fn from(error: io::Error) -> Self {
+        LexError::Io(error.kind(), error.to_string(), Span::new(0, 0))
+    }
+}
+impl StdError for LexError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TriviaKind {
+    Whitespace,
+    Comment, // '#' 到行尾, 不包括换行
+}
+
+// whitespace or a comment, kept by the lossless lexing mode
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+
+// A token together with its source text and the trivia around it.
+// Trailing trivia runs up to the end of the token's line (the newline
+// excluded); everything after that is leading trivia of the next token.
+// For valid UTF-8 input, concatenating `to_source()` of every token up to
+// and including Eof gives back the original text byte-for-byte.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct LosslessToken {
+    pub tok: Token,
+    pub text: String,
+    pub span: Span,
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
+}
+impl LosslessToken {
+    pub fn to_source(&self) -> String {
+        let mut source = String::new();
+        self.leading.iter().for_each(|trivia| source.push_str(&trivia.text));
+        source.push_str(&self.text);
+        self.trailing.iter().for_each(|trivia| source.push_str(&trivia.text));
+        source
+    }
+}
+
+// 单个 token 的默认长度上限(字节)
+pub const DEFAULT_MAX_TOKEN_LEN: usize = 64 * 1024;
+
 #[derive(Debug, Clone)]
-pub struct Lexer<R: Read> {
+pub struct Lexer<R: Source> {
     source: R, // 使用泛型 R 替代固定的 Stdin
     last_char: CharState,
     identifier_str: String,
     num_val: Option<f64>,
+    error: Option<LexError>, // 当前 token 的词法错误
+    io_error: Option<LexError>, // 读取失败后在 Eof 处报告
+    invalid_utf8: bool,         // last_char 是由无效的字节序列得到的 U+FFFD
+    keywords: KeywordTable,
+    cur_tok: Token,
+    pos: usize,       // 已读取的字节数
+    char_pos: usize,  // last_char 在输入中的字节偏移
+    tok_start: usize, // 当前 token 的起始字节偏移
+    raw: Vec<u8>,     // 尚未取出的原始字节, 最后是 last_char 的字节; 用于取出 token 和 trivia 的原文
+    taken: usize,     // 源码在内存中时代替 raw: 尚未取出的原文从这里开始
+    max_token_len: usize,
+    checkpoints: usize,    // 还没有 restore 或 commit 的检查点个数
+    history: Vec<u8>,      // 有检查点时从输入读到的字节, 从 history_start 开始
+    history_start: usize,
+    replay: VecDeque<u8>,  // restore 后要重新读的字节, 先于 source 读取
+}
+
+// Lexer state saved by `Lexer::checkpoint`. Restoring it rewinds the lexer
+// to the token that was current when it was taken.
+#[derive(Debug)]
+pub struct Checkpoint {
+    last_char: CharState,
+    identifier_str: String,
+    num_val: Option<f64>,
+    error: Option<LexError>,
+    io_error: Option<LexError>,
+    invalid_utf8: bool,
     cur_tok: Token,
+    pos: usize,
+    char_pos: usize,
+    tok_start: usize,
+    raw: Vec<u8>,
+    taken: usize,
+}
+
+// Where a lexer gets its input. Any `Read` is read one byte at a time and
+// the text of tokens is copied out of it; a source that already holds the
+// whole text returns it from `text`, and the lexer then decodes characters
+// and slices names, numbers and token text directly out of that string.
+pub trait Source {
+    // 读一个字节, 输入结束时返回 UnexpectedEof
+    fn read_byte(&mut self) -> io::Result<u8>;
+    fn text(&self) -> Option<&str> {
+        None
+    }
+}
+impl<R: Read> Source for R {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut buf = [0u8];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+}
+
+// In-memory input for `Lexer::from_str`. The lexer works on the string by
+// byte offset, so the text of a token is borrowed from the source instead
+// of being copied out of the lexer. `read_byte` still walks the string so
+// that it behaves like any other source.
+#[derive(Debug, Clone, Copy)]
+pub struct StrSource<'a> {
+    text: &'a str,
+    pos: usize,
+}
+impl<'a> StrSource<'a> {
+    pub fn new(text: &'a str) -> Self {
+        StrSource { text, pos: 0 }
+    }
+}
+impl Source for StrSource<'_> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let byte = *self
+            .text
+            .as_bytes()
+            .get(self.pos)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+    fn text(&self) -> Option<&str> {
+        Some(self.text)
+    }
 }
 
-impl<R: Read> Lexer<R> {
+impl<R: Source> Lexer<R> {
     pub fn new(source: R) -> io::Result<Self> {
+        Lexer::with_keywords(source, KeywordTable::new())
+    }
+
+    // 标准关键字加上 language 中的别名
+    pub fn with_language(source: R, language: &LanguageConfig) -> io::Result<Self> {
+        let mut keywords = KeywordTable::new();
+        language.apply(&mut keywords);
+        Lexer::with_keywords(source, keywords)
+    }
+
+    pub fn with_keywords(source: R, keywords: KeywordTable) -> io::Result<Self> {
         Ok(Lexer {
-            source: source,
+            source,
             last_char: CharState::NotInitailized, // 初始化为空格以跳过前导空格
             identifier_str: String::new(),
             num_val: None,
+            error: None,
+            io_error: None,
+            invalid_utf8: false,
+            keywords,
             cur_tok: Token::None,
+            pos: 0,
+            char_pos: 0,
+            tok_start: 0,
+            raw: Vec::new(),
+            taken: 0,
+            max_token_len: DEFAULT_MAX_TOKEN_LEN,
+            checkpoints: 0,
+            history: Vec::new(),
+            history_start: 0,
+            replay: VecDeque::new(),
         })
     }
 
+    // Saves the current position. Every checkpoint must be given back with
+    // `restore` or `commit`, innermost first; until then the bytes read
+    // from the source are kept so they can be read again.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        if self.checkpoints == 0 {
+            self.history.clear();
+            self.history_start = self.pos;
+        }
+        self.checkpoints += 1;
+        Checkpoint {
+            last_char: self.last_char,
+            identifier_str: self.identifier_str.clone(),
+            num_val: self.num_val,
+            error: self.error.clone(),
+            io_error: self.io_error.clone(),
+            invalid_utf8: self.invalid_utf8,
+            cur_tok: self.cur_tok,
+            pos: self.pos,
+            char_pos: self.char_pos,
+            tok_start: self.tok_start,
+            raw: self.raw.clone(),
+            taken: self.taken,
+        }
+    }
+
+    // 回到检查点, 之后读过的字节会被重新读一遍
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        // 内存中的源码只要回到原来的位置
+        if self.source.text().is_none() {
+            let read = self.history.split_off(checkpoint.pos - self.history_start);
+            for byte in read.into_iter().rev() {
+                self.replay.push_front(byte);
+            }
+        }
+        self.last_char = checkpoint.last_char;
+        self.identifier_str = checkpoint.identifier_str;
+        self.num_val = checkpoint.num_val;
+        self.error = checkpoint.error;
+        self.io_error = checkpoint.io_error;
+        self.invalid_utf8 = checkpoint.invalid_utf8;
+        self.cur_tok = checkpoint.cur_tok;
+        self.pos = checkpoint.pos;
+        self.char_pos = checkpoint.char_pos;
+        self.tok_start = checkpoint.tok_start;
+        self.raw = checkpoint.raw;
+        self.taken = checkpoint.taken;
+        self.release();
+    }
+
+    // 不再需要回到检查点
+    pub fn commit(&mut self, _checkpoint: Checkpoint) {
+        self.release();
+    }
+
+    fn release(&mut self) {
+        self.checkpoints -= 1;
+        if self.checkpoints == 0 {
+            self.history.clear();
+        }
+    }
+
+    // 先读 restore 后要重新读的字节, 再读 source
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let byte = match self.replay.pop_front() {
+            Some(byte) => byte,
+            None => self.source.read_byte()?,
+        };
+        if self.checkpoints > 0 {
+            self.history.push(byte);
+        }
+        Ok(byte)
+    }
+
+    // 放回刚读到的字节, 下次 read_byte 再读一遍
+    fn unread_byte(&mut self, byte: u8) {
+        if self.checkpoints > 0 {
+            self.history.pop();
+        }
+        self.replay.push_front(byte);
+    }
+
+    // 按 UTF-8 解码读取一个字符, 无效的字节序列读作 U+FFFD
     pub fn get_char(&mut self) {
-        let mut buf = [0u8; 1];
-        match self.source.read_exact(&mut buf) {
-            Ok(_) => {
-                self.last_char = CharState::Char(buf[0] as char);
+        self.char_pos = self.pos;
+        // 源码在内存中时直接按下标解码, 不用逐字节读取, 也不用 raw
+        if let Some(text) = self.source.text() {
+            self.last_char = match text[self.pos..].chars().next() {
+                Some(c) => {
+                    self.pos += c.len_utf8();
+                    CharState::Char(c)
+                }
+                None => CharState::Eof,
+            };
+            return;
+        }
+        let mut buf = [0u8; 4];
+        match self.read_byte() {
+            Ok(byte) => {
+                buf[0] = byte;
+                // 首字节决定字符的字节数
+                let len = match buf[0] {
+                    0xc0..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    0xf0..=0xf7 => 4,
+                    _ => 1,
+                };
+                let mut read = 1;
+                while read < len {
+                    match self.read_byte() {
+                        Ok(byte @ 0x80..=0xbf) => buf[read] = byte,
+                        // 不是后续字节时字符到此为止, 这个字节属于下一个字符
+                        Ok(byte) => {
+                            self.unread_byte(byte);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                    read += 1;
+                }
+                self.pos += read;
+                // 超长的 token 或 trivia 只保留开头的原文, 不让缓冲区无限增长
+                if self.raw.len() > self.max_token_len {
+                    self.raw.truncate(self.max_token_len);
+                }
+                self.raw.extend_from_slice(&buf[..read]);
+                let c = str::from_utf8(&buf[..read])
+                    .ok()
+                    .and_then(|text| text.chars().next());
+                self.invalid_utf8 = c.is_none();
+                self.last_char = CharState::Char(c.unwrap_or(char::REPLACEMENT_CHARACTER));
             }
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                 self.last_char = CharState::Eof;
             }
+            // 读取失败后当作输入结束, 不再重试; 错误随 Eof 一起报告
             Err(e) => {
-                eprintln!("文件读取失败: {}", e);
+                let span = Span::new(self.pos, self.pos);
+                self.io_error = Some(LexError::Io(e.kind(), e.to_string(), span));
+                self.last_char = CharState::Eof;
             }
         }
     }
 
-    pub fn get_token(&mut self) -> Token {
-        // 跳过空格
-        while self.last_char == CharState::Char(' ') || self.last_char == CharState::NotInitailized
-        {
+    // 读取下一个 token. 有词法错误时返回错误, 之后可以继续读取
+    pub fn get_token(&mut self) -> Result<Token, LexError> {
+        let tok = self.next_token();
+        match &self.error {
+            Some(error) => Err(error.clone()),
+            None => Ok(tok),
+        }
+    }
+
+    // 出错时也返回 token 的种类, 错误留在 self.error 中, 供 parser 使用
+    fn next_token(&mut self) -> Token {
+        if self.last_char == CharState::NotInitailized {
+            self.get_char();
+        }
+        // 跳过空白字符(包括换行, 支持多行输入)和注释, 不需要它们的原文
+        while self.skip_trivia(false).is_some() {}
+        let tok = self.lex_token();
+        self.discard_text(self.token_end());
+        tok
+    }
+
+    // Lossless mode: like `get_token`, but keeps the token's text and the
+    // whitespace and comments around it instead of discarding them.
+    pub fn get_lossless_token(&mut self) -> LosslessToken {
+        if self.last_char == CharState::NotInitailized {
             self.get_char();
         }
+        let mut leading = Vec::new();
+        while let Some(trivia) = self.lex_trivia(false) {
+            leading.push(trivia);
+        }
+        let tok = self.lex_token();
+        let span = self.token_span();
+        let text = self.take_text(span.end);
+        let mut trailing = Vec::new();
+        while let Some(trivia) = self.lex_trivia(true) {
+            trailing.push(trivia);
+        }
+        LosslessToken {
+            tok,
+            text,
+            span,
+            leading,
+            trailing,
+        }
+    }
+
+    // 读取一段空白或注释, 当前字符不是 trivia 时返回 None.
+    // same_line 为 true 时遇到换行就停止
+    fn lex_trivia(&mut self, same_line: bool) -> Option<Trivia> {
+        let start = self.char_pos;
+        let kind = self.skip_trivia(same_line)?;
+        let end = self.token_end();
+        Some(Trivia {
+            kind,
+            text: self.take_text(end),
+            span: Span::new(start, end),
+        })
+    }
+    fn skip_trivia(&mut self, same_line: bool) -> Option<TriviaKind> {
+        let kind = match self.last_char {
+            CharState::Char('\n') if same_line => return None,
+            CharState::Char(c) if classify_char(c) == CharClass::Whitespace => {
+                while let CharState::Char(c) = self.last_char {
+                    if !c.is_whitespace() || (same_line && c == '\n') {
+                        break;
+                    }
+                    self.get_char();
+                }
+                TriviaKind::Whitespace
+            }
+            CharState::Char(c) if classify_char(c) == CharClass::CommentStart => {
+                while !matches!(self.last_char, CharState::Char('\n') | CharState::Eof) {
+                    self.get_char();
+                }
+                TriviaKind::Comment
+            }
+            _ => return None,
+        };
+        Some(kind)
+    }
 
+    // 取出 end 之前尚未取出的原文. end 之后只剩 last_char, 它的字节总在 raw 末尾
+    fn take_text(&mut self, end: usize) -> String {
+        let text = match self.source.text() {
+            // 和 raw 一样, 超长的原文只保留开头
+            Some(text) => {
+                let text = &text[self.taken..end];
+                text[..text.floor_char_boundary(self.max_token_len)].to_string()
+            }
+            None => {
+                let len = self.raw.len() - (self.pos - end);
+                String::from_utf8_lossy(&self.raw[..len]).into_owned()
+            }
+        };
+        self.discard_text(end);
+        text
+    }
+    fn discard_text(&mut self, end: usize) {
+        if self.source.text().is_none() {
+            let len = self.raw.len() - (self.pos - end);
+            self.raw.drain(..len);
+        }
+        self.taken = end;
+    }
+
+    // The lexer as a state machine. Each call starts on the first unread
+    // character, which is classified with `classify_char`:
+    //   Whitespace      -> skip while whitespace, back to start
+    //   CommentStart    -> skip to the end of the line, back to start
+    //   IdentifierStart -> read while `is_identifier_char`; emit the keyword
+    //                      token if the word is in the keyword table,
+    //                      otherwise Identifier
+    //   NumberStart     -> read while `is_number_char`, then validate the
+    //                      whole literal; emit Number (num_val is None and
+    //                      `error` is set if it is malformed), or
+    //                      Ellipsis if the literal is exactly `...`
+    //   Symbol          -> emit the token of the symbol, two characters
+    //                      long for -> && ||, or Unknown(c)
+    //   end of input    -> emit Eof, on every call from then on
+    // Whitespace and comments are handled by `lex_trivia` before this runs.
+    // Identifiers and numbers longer than max_token_len are read to the end
+    // and reported as TokenTooLong.
+    fn lex_token(&mut self) -> Token {
+        self.tok_start = self.char_pos;
+        self.error = None;
+
+        let class = match self.last_char {
+            CharState::Char(c) => Some(classify_char(c)),
+            _ => None,
+        };
         match self.last_char {
             // determine whether is eof
-            CharState::Eof => return Token::Eof,
+            CharState::Eof => {
+                self.error = self.io_error.take();
+                Token::Eof
+            }
 
             // determin whether is identifier eof extern
-            CharState::Char(c) if c.is_alphabetic() => {
+            CharState::Char(c) if class == Some(CharClass::IdentifierStart) => {
+                // 源码在内存中时名字就是源码的切片, 不用复制
+                let copy = self.source.text().is_none();
                 self.identifier_str.clear();
-                self.identifier_str.push(c);
+                if copy {
+                    self.identifier_str.push(c);
+                }
                 loop {
                     self.get_char();
                     match self.last_char {
-                        CharState::Char(this_c) if this_c.is_alphanumeric() => {
-                            self.identifier_str.push(this_c);
+                        CharState::Char(this_c) if is_identifier_char(this_c) => {
+                            // 超过上限后只读不存, 读完整个 token 再报错
+                            if copy && self.identifier_str.len() < self.max_token_len {
+                                self.identifier_str.push(this_c);
+                            }
                         }
                         _ => break,
                     }
                 }
 
-                match self.identifier_str.as_str() {
-                    "def" => Token::Def,
-                    "extern" => Token::Extern,
-                    _ => Token::Identifier,
+                if self.check_token_len() {
+                    return Token::Identifier;
                 }
+                self.keywords
+                    .get(self.identifier_text())
+                    .unwrap_or(Token::Identifier)
             }
 
-            CharState::Char(c) if c.is_numeric() || c == '.' => {
+            CharState::Char(_) if class == Some(CharClass::NumberStart) => {
+                // 先读入整个字面量(包括紧跟的字母等), 再检查格式
+                let copy = self.source.text().is_none();
                 let mut number_str = String::new();
-                loop {
-                    if let CharState::Char(num_c) = self.last_char {
-                        number_str.push(num_c);
-                        self.get_char();
-
-                        match self.last_char {
-                            CharState::Char(next_c) if next_c.is_numeric() || next_c == '.' => {
-                                continue;
-                            }
-                            _ => break,
-                        }
-                    } else {
+                let mut prev = None;
+                while let CharState::Char(num_c) = self.last_char {
+                    if !is_number_char(num_c, prev) {
                         break;
                     }
+                    if copy && number_str.len() < self.max_token_len {
+                        number_str.push(num_c);
+                    }
+                    prev = Some(num_c);
+                    self.get_char();
+                }
+                if self.check_token_len() {
+                    self.num_val = None;
+                    return Token::Number;
+                }
+                let span = Span::new(self.tok_start, self.token_end());
+                let text = match self.source.text() {
+                    Some(text) => &text[span.start..span.end],
+                    None => &number_str,
+                };
+                // 省略号也以 '.' 开头, 但不是数字
+                if text == "..." {
+                    return Token::Ellipsis;
+                }
+                self.num_val = parse_number(text);
+                if self.num_val.is_none() {
+                    self.error = Some(LexError::MalformedNumber(text.to_string(), span));
                 }
-                self.num_val = number_str.parse::<f64>().ok();
                 Token::Number
             }
 
             CharState::Char(c) => {
+                let invalid_utf8 = self.invalid_utf8;
                 self.get_char();
-                Token::Char(c)
+                if invalid_utf8 {
+                    let span = Span::new(self.tok_start, self.token_end());
+                    self.error = Some(LexError::InvalidUtf8(span));
+                }
+                // 两个字符的符号: -> && ||; 单独的 & 和 | 不是符号
+                let mut pair = [0; 8];
+                if let CharState::Char(next) = self.last_char
+                    && let Some(tok) = punctuation_token(encode_pair(c, next, &mut pair))
+                {
+                    self.get_char();
+                    return tok;
+                }
+                punctuation_token(c.encode_utf8(&mut [0; 4])).unwrap_or(Token::Unknown(c))
             }
             CharState::NotInitailized => unreachable!(),
         }
     }
 
+    // 刚读完的 token 超过长度上限时记录错误并返回 true
+    fn check_token_len(&mut self) -> bool {
+        let span = Span::new(self.tok_start, self.token_end());
+        if span.end - span.start <= self.max_token_len {
+            return false;
+        }
+        self.error = Some(LexError::TokenTooLong(span, self.max_token_len));
+        true
+    }
+
     pub fn update_token(&mut self) -> Token {
-        self.cur_tok = self.get_token();
-        return self.cur_tok;
+        self.cur_tok = self.next_token();
+        self.cur_tok
+    }
+
+    // Name of the current identifier or keyword. For in-memory sources it
+    // is a slice of the source; otherwise it is the copy read from the
+    // input, cut off at max_token_len.
+    pub fn identifier_text(&self) -> &str {
+        match self.source.text() {
+            Some(text) => &text[self.tok_start..self.token_end()],
+            None => &self.identifier_str,
+        }
+    }
+
+    pub fn register_keyword(&mut self, word: &str, tok: Token) {
+        self.keywords.insert(word, tok);
+    }
+    pub fn keywords(&self) -> &KeywordTable {
+        &self.keywords
+    }
+
+    // Upper bound in bytes for identifiers and numbers; longer tokens are
+    // read to the end but reported as `LexError::TokenTooLong`. Trivia
+    // longer than this keep only their beginning in lossless mode.
+    pub fn set_max_token_len(&mut self, max_token_len: usize) {
+        self.max_token_len = max_token_len;
+    }
+    pub fn max_token_len(&self) -> usize {
+        self.max_token_len
+    }
+
+    // 当前 token 的词法错误, 例如格式错误的数字
+    pub fn error(&self) -> Option<&LexError> {
+        self.error.as_ref()
+    }
+
+    // 当前 token 在输入中的字节范围 [start, end)
+    pub fn token_span(&self) -> Span {
+        Span::new(self.token_start(), self.token_end())
+    }
+    pub fn token_start(&self) -> usize {
+        self.tok_start
+    }
+    pub fn token_end(&self) -> usize {
+        match self.last_char {
+            CharState::Char(_) => self.char_pos,
+            _ => self.pos,
+        }
+    }
+}
+
+impl<'a> Lexer<StrSource<'a>> {
+    // 直接在内存中的源码上分析, 不会出现读取错误.
+    // 返回的 lexer 借用 text, 所以不能实现 FromStr
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(text: &'a str) -> Self {
+        Lexer::from_str_with_keywords(text, KeywordTable::new())
+    }
+    pub fn from_str_with_keywords(text: &'a str, keywords: KeywordTable) -> Self {
+        Lexer::with_keywords(StrSource::new(text), keywords).unwrap()
+    }
+
+    pub fn source_text(&self) -> &'a str {
+        self.source.text
+    }
+
+    // Text of the current token, borrowed from the source. It is never
+    // truncated at max_token_len.
+    pub fn token_text(&self) -> &'a str {
+        let span = self.token_span();
+        &self.source.text[span.start..span.end]
+    }
+
+    // 当前 token 是标识符(或关键字)时它的名字
+    pub fn identifier(&self) -> Option<&'a str> {
+        match self.cur_tok {
+            Token::Identifier | Token::Def | Token::Extern | Token::Keyword(_) => {
+                Some(self.token_text())
+            }
+            _ => None,
+        }
+    }
+}
+
+// 把两个字符写进 buf, 不用为每个符号分配字符串
+fn encode_pair(first: char, second: char, buf: &mut [u8; 8]) -> &str {
+    let len = first.encode_utf8(buf).len();
+    let len = len + second.encode_utf8(&mut buf[len..]).len();
+    str::from_utf8(&buf[..len]).unwrap()
+}
+
+// number ::= digits ('.' digits?)? exponent? | '.' digits exponent?
+// exponent ::= ('e' | 'E') ('+' | '-')? digits
+// digits 中可以用单个 '_' 分隔数字, 例如 1_000
+fn parse_number(text: &str) -> Option<f64> {
+    fn is_digits(part: &str) -> bool {
+        part.split('_')
+            .all(|group| !group.is_empty() && group.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(i) => (&text[..i], Some(&text[i + 1..])),
+        None => (text, None),
+    };
+    let valid_mantissa = match mantissa.split_once('.') {
+        Some(("", frac)) => is_digits(frac),
+        Some((int, "")) => is_digits(int),
+        Some((int, frac)) => is_digits(int) && is_digits(frac),
+        None => is_digits(mantissa),
+    };
+    let valid_exponent = match exponent {
+        Some(exp) => is_digits(exp.strip_prefix(['+', '-']).unwrap_or(exp)),
+        None => true,
+    };
+    if !(valid_mantissa && valid_exponent) {
+        return None;
     }
+    text.replace('_', "").parse::<f64>().ok()
 }
 
 #[cfg(test)]
@@ -182,89 +1092,655 @@ mod test_lexer {
     #[test]
     fn test_skip_spaces() {
         let mut lexer1 = create_lexer("   a");
-        assert!(matches!(lexer1.get_token(), Token::Identifier));
-        //assert!(matches!(lexer1.get_token(), Token::Eof));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Identifier));
+        //assert!(matches!(lexer1.get_token().unwrap(), Token::Eof));
         // assert_eq!(lexer.last_char, Some('a')); // 正确停在第一个非空格字符
     }
     #[test]
     fn test_eof() {
         let mut lexer1 = create_lexer("");
-        assert!(matches!(lexer1.get_token(), Token::Eof));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Eof));
         let mut lexer2 = create_lexer("    ");
-        assert!(matches!(lexer2.get_token(), Token::Eof));
+        assert!(matches!(lexer2.get_token().unwrap(), Token::Eof));
     }
 
     #[test]
     fn test_def() {
         let mut lexer1 = create_lexer("def");
-        assert!(matches!(lexer1.get_token(), Token::Def));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Def));
         let mut lexer2 = create_lexer("   def  ");
-        assert!(matches!(lexer2.get_token(), Token::Def));
-        assert!(matches!(lexer1.get_token(), Token::Eof));
+        assert!(matches!(lexer2.get_token().unwrap(), Token::Def));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Eof));
     }
 
     #[test]
     fn test_extern() {
         let mut lexer1 = create_lexer("extern");
-        assert!(matches!(lexer1.get_token(), Token::Extern));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Extern));
         let mut lexer2 = create_lexer("   extern  ");
-        assert!(matches!(lexer2.get_token(), Token::Extern));
-        assert!(matches!(lexer1.get_token(), Token::Eof));
+        assert!(matches!(lexer2.get_token().unwrap(), Token::Extern));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Eof));
     }
     #[test]
-    fn test_identifier() {
-        let mut lexer1 = create_lexer("abc");
-        //assert!(matches!(lexer1.identifier_str.as_str(), "abc"));
-        //assert_eq!(lexer1.identifier_str.as_str(), "abc");
-        assert!(matches!(lexer1.get_token(), Token::Identifier));
-        assert!(matches!(lexer1.get_token(), Token::Eof));
+    fn test_register_keyword() {
+        let mut lexer1 = create_lexer("fn def fnord");
+        lexer1.register_keyword("fn", Token::Def);
+        assert_eq!(lexer1.get_token().unwrap(), Token::Def);
+        assert_eq!(lexer1.get_token().unwrap(), Token::Def);
+        assert_eq!(lexer1.get_token().unwrap(), Token::Identifier);
+
+        // 不带 def 的关键字表, def 只是普通标识符
+        let mut keywords = KeywordTable::empty();
+        keywords.insert("fn", Token::Def);
+        let source = MockReader {
+            data: b"def fn".to_vec(),
+            position: 0,
+        };
+        let mut lexer2 = Lexer::with_keywords(source, keywords).unwrap();
+        assert_eq!(lexer2.get_token().unwrap(), Token::Identifier);
+        assert_eq!(lexer2.get_token().unwrap(), Token::Def);
     }
 
     #[test]
-    fn test_number() {
-        let mut lexer1 = create_lexer("1.234");
-        assert!(matches!(lexer1.get_token(), Token::Number));
-        assert!(matches!(lexer1.num_val, Some::<f64>(1.234)));
-        assert!(matches!(lexer1.get_token(), Token::Eof));
-        let mut lexer2 = create_lexer(".234");
-        assert!(matches!(lexer2.get_token(), Token::Number));
-        assert!(matches!(lexer2.num_val, Some::<f64>(0.234)));
+    fn test_comments() {
+        let mut lexer1 = create_lexer("# leading comment\ndef f(x) # trailing\n  x#no space\n#");
+        assert_eq!(lexer1.get_token().unwrap(), Token::Def);
+        assert_eq!(lexer1.token_span(), Span::new(18, 21));
+        assert_eq!(lexer1.get_token().unwrap(), Token::Identifier);
+        assert_eq!(lexer1.get_token().unwrap(), Token::LParen);
+        assert_eq!(lexer1.get_token().unwrap(), Token::Identifier);
+        assert_eq!(lexer1.get_token().unwrap(), Token::RParen);
+        assert_eq!(lexer1.get_token().unwrap(), Token::Identifier);
+        assert_eq!(lexer1.identifier_str, "x");
+        assert_eq!(lexer1.get_token().unwrap(), Token::Eof);
+    }
+
+    #[test]
+    fn test_lossless_tokens() {
+        let input = "# add\ndef add(x y)  # sum\n  x+y\n\n";
+        let mut lexer1 = create_lexer(input);
+        let mut tokens = Vec::new();
+        loop {
+            let token = lexer1.get_lossless_token();
+            let tok = token.tok;
+            tokens.push(token);
+            if tok == Token::Eof {
+                break;
+            }
+        }
+        let source: String = tokens.iter().map(LosslessToken::to_source).collect();
+        assert_eq!(source, input);
+
+        // 注释和换行是 def 的前导 trivia
+        let def = &tokens[0];
+        assert_eq!((def.tok, def.text.as_str()), (Token::Def, "def"));
+        assert_eq!(def.span, Span::new(6, 9));
+        let leading: Vec<_> = def.leading.iter().map(|t| (t.kind, t.text.as_str())).collect();
+        assert_eq!(
+            leading,
+            [(TriviaKind::Comment, "# add"), (TriviaKind::Whitespace, "\n")]
+        );
+        assert_eq!(def.trailing[0].text, " ");
+
+        // 同一行的注释是 ')' 的后缀 trivia, 换行属于下一个 token
+        let close = &tokens[5];
+        assert_eq!(close.tok, Token::RParen);
+        let trailing: Vec<_> = close.trailing.iter().map(|t| (t.kind, t.text.as_str())).collect();
+        assert_eq!(
+            trailing,
+            [(TriviaKind::Whitespace, "  "), (TriviaKind::Comment, "# sum")]
+        );
+        assert_eq!(tokens[6].leading[0].text, "\n  ");
+        assert_eq!(tokens[6].leading[0].span, Span::new(25, 28));
+
+        let eof = tokens.last().unwrap();
+        assert_eq!(eof.text, "");
+        assert_eq!(eof.leading[0].text, "\n\n");
+    }
+
+    #[test]
+    fn test_utf8_input() {
+        let mut lexer1 = create_lexer("变量 + é");
+        assert_eq!(lexer1.get_token().unwrap(), Token::Identifier);
+        assert_eq!(lexer1.identifier_str, "变量");
+        assert_eq!(lexer1.token_span(), Span::new(0, 6));
+        assert_eq!(lexer1.get_token().unwrap(), Token::Op(Operator::Plus));
+        assert_eq!(lexer1.token_span(), Span::new(7, 8));
+        assert_eq!(lexer1.get_token().unwrap(), Token::Identifier);
+        assert_eq!(lexer1.identifier_str, "é");
+        assert_eq!(lexer1.get_token().unwrap(), Token::Eof);
+
+        let source = MockReader {
+            data: vec![0xff, b' ', 0xe5, 0x8f],
+            position: 0,
+        };
+        let mut lexer2 = Lexer::new(source).unwrap();
+        assert_eq!(lexer2.get_token(), Err(LexError::InvalidUtf8(Span::new(0, 1))));
+        assert_eq!(lexer2.get_token(), Err(LexError::InvalidUtf8(Span::new(2, 4))));
+        assert_eq!(lexer2.get_token(), Ok(Token::Eof));
+        // 不完整的字符不会吞掉后面的 '(', 和 from_str 读到的 token 一致
+        let mut lexer4 = Lexer::new(&b"\xC3(1)"[..]).unwrap();
+        let text = String::from_utf8_lossy(b"\xC3(1)");
+        let mut lexer5 = Lexer::from_str(&text);
+        assert_eq!(lexer4.get_token(), Err(LexError::InvalidUtf8(Span::new(0, 1))));
+        assert_eq!(lexer5.get_token(), Ok(Token::Unknown(char::REPLACEMENT_CHARACTER)));
+        for tok in [Token::LParen, Token::Number, Token::RParen, Token::Eof] {
+            assert_eq!(lexer4.get_token(), Ok(tok));
+            assert_eq!(lexer5.get_token(), Ok(tok));
+        }
+        // 源码中本来就有的 U+FFFD 不是错误
+        let mut lexer3 = create_lexer("\u{fffd}");
+        assert_eq!(lexer3.get_token(), Ok(Token::Unknown(char::REPLACEMENT_CHARACTER)));
+    }
+
+    // 读完 data 之后读取失败
+    struct FailingReader {
+        data: &'static [u8],
+    }
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::Error::other("disk on fire"));
+            }
+            let len = buf.len().min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_io_error() {
+        let mut lexer1 = Lexer::new(FailingReader { data: b"x + y" }).unwrap();
+        assert_eq!(lexer1.get_token(), Ok(Token::Identifier));
+        assert_eq!(lexer1.get_token(), Ok(Token::Op(Operator::Plus)));
+        assert_eq!(lexer1.get_token(), Ok(Token::Identifier));
+        let error = lexer1.get_token().unwrap_err();
+        assert_eq!(
+            error,
+            LexError::Io(io::ErrorKind::Other, "disk on fire".to_string(), Span::new(5, 5))
+        );
+        assert_eq!(error.to_string(), "failed to read input at 5: disk on fire");
+        // 只报告一次, 之后停在 Eof, 不会反复读取
+        assert_eq!(lexer1.get_token(), Ok(Token::Eof));
+        assert_eq!(lexer1.get_token(), Ok(Token::Eof));
+    }
+
+    #[test]
+    fn test_language_aliases() {
+        let source = MockReader {
+            data: "定义 加一(x) x 加 1\n外部 sin(a)".as_bytes().to_vec(),
+            position: 0,
+        };
+        let mut lexer1 = Lexer::with_language(source, &LanguageConfig::chinese()).unwrap();
+        let mut tokens = Vec::new();
+        loop {
+            let tok = lexer1.get_token().unwrap();
+            tokens.push(tok);
+            if tok == Token::Eof {
+                break;
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Def,
+                Token::Identifier,
+                Token::LParen,
+                Token::Identifier,
+                Token::RParen,
+                Token::Identifier,
+                Token::Op(Operator::Plus),
+                Token::Number,
+                Token::Extern,
+                Token::Identifier,
+                Token::LParen,
+                Token::Identifier,
+                Token::RParen,
+                Token::Eof,
+            ]
+        );
+        // 原来的关键字仍然可用
+        assert_eq!(lexer1.keywords().get("def"), Some(Token::Def));
+
+        // 未知的规范写法被忽略
+        let mut config = LanguageConfig::new();
+        config.alias("当", "while");
+        assert_eq!(config.canonical("当"), Some("while"));
+        let mut keywords = KeywordTable::new();
+        config.apply(&mut keywords);
+        assert_eq!(keywords.get("当"), None);
+        keywords.insert("while", Token::Keyword(Keyword::In));
+        config.apply(&mut keywords);
+        assert_eq!(keywords.get("当"), Some(Token::Keyword(Keyword::In)));
+    }
+
+    #[test]
+    fn test_identifier() {
+        let mut lexer1 = create_lexer("abc");
+        //assert!(matches!(lexer1.identifier_str.as_str(), "abc"));
+        //assert_eq!(lexer1.identifier_str.as_str(), "abc");
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Identifier));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Eof));
+    }
+
+    #[test]
+    fn test_number() {
+        let mut lexer1 = create_lexer("1.234");
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Number));
+        assert!(matches!(lexer1.num_val, Some::<f64>(1.234)));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Eof));
+        let mut lexer2 = create_lexer(".234");
+        assert!(matches!(lexer2.get_token().unwrap(), Token::Number));
+        assert!(matches!(lexer2.num_val, Some::<f64>(0.234)));
         let mut lexer2 = create_lexer("       .234");
-        assert!(matches!(lexer2.get_token(), Token::Number));
+        assert!(matches!(lexer2.get_token().unwrap(), Token::Number));
         assert!(matches!(lexer2.num_val, Some::<f64>(0.234)));
     }
     // let mut lexer2 = create_lexer("12.3");
-    // assert!(matches!(lexer2.get_token(),Token::Number));
+    // assert!(matches!(lexer2.get_token().unwrap(),Token::Number));
+
+    #[test]
+    fn test_number_literals() {
+        let cases = [
+            ("1e10", 1e10),
+            ("2.5E-3", 2.5e-3),
+            ("4e+2", 400.0),
+            ("1_000", 1000.0),
+            ("1_000.000_5", 1000.0005),
+            ("3.", 3.0),
+            (".5e1", 5.0),
+        ];
+        for (input, expected) in cases {
+            let mut lexer1 = create_lexer(input);
+            assert_eq!(lexer1.get_token().unwrap(), Token::Number, "{}", input);
+            assert_eq!(lexer1.num_val, Some(expected), "{}", input);
+            assert_eq!(lexer1.error(), None);
+            assert_eq!(lexer1.get_token().unwrap(), Token::Eof);
+        }
+    }
+
+    #[test]
+    fn test_malformed_numbers() {
+        for input in ["1.2.3", "1e", "1e+", "1__0", "1_", "1_.5", "12abc", ".", "._1"] {
+            let mut lexer1 = create_lexer(input);
+            assert_eq!(
+                lexer1.get_token(),
+                Err(LexError::MalformedNumber(input.to_string(), Span::new(0, input.len())))
+            );
+            assert_eq!(lexer1.num_val, None, "{}", input);
+        }
+
+        // 出错后从字面量之后继续
+        let mut lexer2 = create_lexer("x + 1.2.3+y");
+        assert_eq!(lexer2.get_token().unwrap(), Token::Identifier);
+        assert_eq!(lexer2.get_token().unwrap(), Token::Op(Operator::Plus));
+        assert_eq!(lexer2.get_token().unwrap_err().span(), Span::new(4, 9));
+        assert_eq!(lexer2.get_token().unwrap(), Token::Op(Operator::Plus));
+        assert_eq!(lexer2.error(), None);
+        assert_eq!(lexer2.get_token().unwrap(), Token::Identifier);
+    }
+
+    #[test]
+    fn test_token_too_long() {
+        // 一百万位数字和一百万个字母, 默认上限下都报错, 且缓冲区不随输入增长
+        let digits = "9".repeat(1 << 20);
+        let letters = "变".repeat(1 << 20);
+        let input = format!("{} + {}+ok", digits, letters);
+        let mut lexer1 = create_lexer(&input);
+        assert_eq!(
+            lexer1.get_token(),
+            Err(LexError::TokenTooLong(Span::new(0, digits.len()), DEFAULT_MAX_TOKEN_LEN))
+        );
+        assert_eq!(lexer1.num_val, None);
+        assert!(lexer1.raw.len() <= DEFAULT_MAX_TOKEN_LEN + 4);
+        assert_eq!(lexer1.get_token().unwrap(), Token::Op(Operator::Plus));
+        assert_eq!(lexer1.error(), None);
+        let start = digits.len() + 3;
+        assert_eq!(lexer1.get_token().unwrap_err().span(), Span::new(start, start + letters.len()));
+        assert!(lexer1.identifier_str.len() <= DEFAULT_MAX_TOKEN_LEN + 4);
+        assert!(lexer1.raw.len() <= DEFAULT_MAX_TOKEN_LEN + 4);
+        assert_eq!(lexer1.get_token().unwrap(), Token::Op(Operator::Plus));
+        assert_eq!(lexer1.get_token().unwrap(), Token::Identifier);
+        assert_eq!(lexer1.identifier_str, "ok");
+        assert_eq!(lexer1.get_token().unwrap(), Token::Eof);
+
+        // 自定义上限, 恰好等于上限的 token 仍然合法; 超长的关键字也不会被识别
+        let mut lexer2 = create_lexer("abcd 1234 abcde 12345 defdef");
+        lexer2.set_max_token_len(4);
+        assert_eq!(lexer2.max_token_len(), 4);
+        let mut errors = Vec::new();
+        while lexer2.next_token() != Token::Eof {
+            errors.push(lexer2.error().map(|error| error.span()));
+        }
+        assert_eq!(
+            errors,
+            [None, None, Some(Span::new(10, 15)), Some(Span::new(16, 21)), Some(Span::new(22, 28))]
+        );
+        assert_eq!(
+            LexError::TokenTooLong(Span::new(10, 15), 4).to_string(),
+            "token at 10..15 is longer than the limit of 4 bytes"
+        );
+    }
+
+    #[test]
+    fn test_lossless_token_too_long() {
+        // 超长的 token 和注释只保留开头, 之后的 token 原文不受影响
+        let input = format!("{} # {}\n+ x", "a".repeat(100), "c".repeat(100));
+        let mut lexer1 = create_lexer(&input);
+        lexer1.set_max_token_len(10);
+        let token = lexer1.get_lossless_token();
+        assert_eq!(token.tok, Token::Identifier);
+        assert_eq!(token.span, Span::new(0, 100));
+        assert_eq!(token.text, "a".repeat(10));
+        assert_eq!(token.trailing[1].text, format!("# {}", "c".repeat(8)));
+        let token = lexer1.get_lossless_token();
+        assert_eq!(token.tok, Token::Op(Operator::Plus));
+        assert_eq!(token.to_source(), "\n+ ");
+        assert_eq!(lexer1.get_lossless_token().text, "x");
+
+        // 在内存中分析时也一样
+        let mut lexer2 = Lexer::from_str(&input);
+        lexer2.set_max_token_len(10);
+        let token = lexer2.get_lossless_token();
+        assert_eq!(token.text, "a".repeat(10));
+        assert_eq!(token.trailing[1].text, format!("# {}", "c".repeat(8)));
+    }
+
+    #[test]
+    fn test_classify_char() {
+        let cases = [
+            (' ', CharClass::Whitespace),
+            ('\n', CharClass::Whitespace),
+            ('#', CharClass::CommentStart),
+            ('x', CharClass::IdentifierStart),
+            ('变', CharClass::IdentifierStart),
+            ('7', CharClass::NumberStart),
+            ('.', CharClass::NumberStart),
+            ('+', CharClass::Symbol),
+            ('_', CharClass::Symbol),
+            ('٣', CharClass::Symbol), // 非 ASCII 数字不能开始数字字面量
+        ];
+        for (c, class) in cases {
+            assert_eq!(classify_char(c), class, "{:?}", c);
+            // 和词法分析器的行为一致
+            let mut lexer1 = create_lexer(&c.to_string());
+            let tok = lexer1.next_token();
+            let expected = match class {
+                CharClass::Whitespace | CharClass::CommentStart => Token::Eof,
+                CharClass::IdentifierStart => Token::Identifier,
+                CharClass::NumberStart => Token::Number,
+                CharClass::Symbol => punctuation_token(&c.to_string()).unwrap_or(Token::Unknown(c)),
+            };
+            assert_eq!(tok, expected, "{:?}", c);
+        }
+        assert!(is_identifier_char('9') && !is_identifier_char('_'));
+        assert!(is_number_char('-', Some('e')) && !is_number_char('-', Some('1')));
+
+        let mut keywords: Vec<_> = KeywordTable::new().iter().map(|(word, _)| word.to_string()).collect();
+        keywords.sort();
+        assert_eq!(keywords, ["def", "extern", "global", "import", "in"]);
+        assert_eq!(KEYWORDS.len(), 5);
+    }
 
     #[test]
     fn test_char() {
         let mut lexer1 = create_lexer("a+b");
-        assert!(matches!(lexer1.get_token(), Token::Identifier));
-        assert!(matches!(lexer1.get_token(), Token::Char('+')));
-        assert!(matches!(lexer1.get_token(), Token::Identifier));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Identifier));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Op(Operator::Plus)));
+        assert!(matches!(lexer1.get_token().unwrap(), Token::Identifier));
+    }
+
+    #[test]
+    fn test_ellipsis() {
+        let mut lexer1 = create_lexer("(x, ...) .. ....");
+        let mut tokens = Vec::new();
+        loop {
+            match lexer1.get_token() {
+                Ok(Token::Eof) => break,
+                tok => tokens.push(tok.map_err(|error| error.span())),
+            }
+        }
+        // .. 和 .... 仍然是写错的数字
+        assert_eq!(
+            tokens,
+            [
+                Ok(Token::LParen),
+                Ok(Token::Identifier),
+                Ok(Token::Comma),
+                Ok(Token::Ellipsis),
+                Ok(Token::RParen),
+                Err(Span::new(9, 11)),
+                Err(Span::new(12, 16)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_punctuation() {
+        let mut lexer1 = create_lexer("(x: bool) -> [a, b]; = - >");
+        let mut tokens = Vec::new();
+        loop {
+            let tok = lexer1.get_token().unwrap();
+            if tok == Token::Eof {
+                break;
+            }
+            tokens.push(tok);
+        }
+        let expected = [
+            Token::LParen,
+            Token::Identifier,
+            Token::Colon,
+            Token::Identifier,
+            Token::RParen,
+            Token::Arrow,
+            Token::LBracket,
+            Token::Identifier,
+            Token::Comma,
+            Token::Identifier,
+            Token::RBracket,
+            Token::Semicolon,
+            Token::Equals,
+            Token::Op(Operator::Minus),
+            Token::Unknown('>'),
+        ];
+        assert_eq!(tokens, expected);
+        // 每个符号的写法都能查回同一个 token
+        for (text, tok) in PUNCTUATION {
+            assert_eq!(tok.spelling(), Some(text));
+            assert_eq!(punctuation_token(text), Some(tok));
+        }
+        for op in Operator::ALL {
+            assert_eq!(Operator::from_char(op.as_char()), Some(op));
+        }
+        assert_eq!(Token::Identifier.spelling(), None);
+        assert_eq!(describe_token(&Token::Arrow), "'->'");
+        assert_eq!(describe_token(&Token::Unknown('>')), "'>'");
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let mut lexer1 = create_lexer("a&&b||c & |&&&");
+        let mut tokens = Vec::new();
+        loop {
+            let token = lexer1.get_lossless_token();
+            if token.tok == Token::Eof {
+                break;
+            }
+            tokens.push((token.tok, token.text));
+        }
+        let expected = [
+            (Token::Identifier, "a"),
+            (Token::Op(Operator::And), "&&"),
+            (Token::Identifier, "b"),
+            (Token::Op(Operator::Or), "||"),
+            (Token::Identifier, "c"),
+            (Token::Unknown('&'), "&"),
+            (Token::Unknown('|'), "|"),
+            (Token::Op(Operator::And), "&&"),
+            (Token::Unknown('&'), "&"),
+        ];
+        assert_eq!(tokens, expected.map(|(tok, text)| (tok, text.to_string())));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut lexer1 = create_lexer("def foo(x) x + 变量");
+        assert_eq!(lexer1.update_token(), Token::Def);
+        let outer = lexer1.checkpoint();
+        assert_eq!(lexer1.update_token(), Token::Identifier);
+        let inner = lexer1.checkpoint();
+        assert_eq!(lexer1.update_token(), Token::LParen);
+        assert_eq!(lexer1.update_token(), Token::Identifier);
+        // 回到 foo, 读过的字节从缓冲区重新读出
+        lexer1.restore(inner);
+        assert_eq!((lexer1.cur_tok, lexer1.identifier_str.as_str()), (Token::Identifier, "foo"));
+        assert_eq!(lexer1.token_span(), Span::new(4, 7));
+        assert_eq!(lexer1.update_token(), Token::LParen);
+        lexer1.restore(outer);
+        assert_eq!(lexer1.cur_tok, Token::Def);
+        let mut tokens = Vec::new();
+        while lexer1.update_token() != Token::Eof {
+            tokens.push((lexer1.cur_tok, lexer1.token_span()));
+        }
+        assert_eq!(tokens[0], (Token::Identifier, Span::new(4, 7)));
+        assert_eq!(tokens.last(), Some(&(Token::Identifier, Span::new(15, 21))));
+        assert_eq!(lexer1.identifier_str, "变量");
+        assert!(lexer1.history.is_empty() && lexer1.replay.is_empty());
+
+        // commit 之后不能回退, 也不再记录读过的字节
+        let mut lexer2 = create_lexer("a + b");
+        let checkpoint = lexer2.checkpoint();
+        assert_eq!(lexer2.get_lossless_token().text, "a");
+        lexer2.commit(checkpoint);
+        assert!(lexer2.history.is_empty());
+        assert_eq!(lexer2.get_lossless_token().text, "+");
+        assert_eq!(lexer2.get_lossless_token().text, "b");
+    }
+
+    #[test]
+    fn test_from_str() {
+        let source = String::from("def 变量(x) x + 1.5 ...");
+        let mut lexer1 = Lexer::from_str(&source);
+        let mut tokens = Vec::new();
+        while lexer1.update_token() != Token::Eof {
+            tokens.push((lexer1.cur_tok, lexer1.token_text(), lexer1.identifier()));
+        }
+        assert_eq!(
+            tokens,
+            [
+                (Token::Def, "def", Some("def")),
+                (Token::Identifier, "变量", Some("变量")),
+                (Token::LParen, "(", None),
+                (Token::Identifier, "x", Some("x")),
+                (Token::RParen, ")", None),
+                (Token::Identifier, "x", Some("x")),
+                (Token::Op(Operator::Plus), "+", None),
+                (Token::Number, "1.5", None),
+                (Token::Ellipsis, "...", None),
+            ]
+        );
+        // 借用的是源码本身, 不是 lexer 里的副本; lexer 也没有复制任何原文
+        let name = tokens[1].1;
+        assert!(source.as_bytes().as_ptr_range().contains(&name.as_ptr()));
+        assert_eq!(lexer1.source_text(), source);
+        assert!(lexer1.raw.is_empty() && lexer1.identifier_str.is_empty());
+
+        // 回到检查点后 token 的原文不变
+        let mut lexer2 = Lexer::from_str("a + bb");
+        lexer2.update_token();
+        let checkpoint = lexer2.checkpoint();
+        lexer2.update_token();
+        lexer2.update_token();
+        assert_eq!(lexer2.identifier(), Some("bb"));
+        lexer2.restore(checkpoint);
+        assert_eq!(lexer2.identifier(), Some("a"));
+
+        // 超长的标识符仍然报错, 但原文完整
+        let long = "x".repeat(20);
+        let mut lexer3 = Lexer::from_str(&long);
+        lexer3.set_max_token_len(8);
+        assert!(lexer3.get_token().is_err());
+        assert_eq!(lexer3.token_text(), long);
+
+        // 和从 Read 读取得到同样的 token, 包括无损模式的原文
+        let source = "def f(x) # doc\n  x + 1e3 ... 1.2.3 变量; 🦀";
+        let mut lexer4 = Lexer::from_str(source);
+        let mut lexer5 = Lexer::new(source.as_bytes()).unwrap();
+        loop {
+            let token = lexer4.get_lossless_token();
+            assert_eq!(token, lexer5.get_lossless_token());
+            assert_eq!(lexer4.error(), lexer5.error());
+            assert_eq!(lexer4.num_val, lexer5.num_val);
+            if token.tok == Token::Eof {
+                break;
+            }
+        }
+
+        // 直接读 StrSource 也按字节走完整个字符串
+        let mut source = StrSource::new("a变");
+        let bytes: Vec<u8> = std::iter::from_fn(|| source.read_byte().ok()).collect();
+        assert_eq!(bytes, "a变".as_bytes());
+        let error = source.read_byte().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_token_position() {
+        let mut lexer1 = create_lexer("  def foo(1.5)\n+ x");
+        let mut ranges = Vec::new();
+        while lexer1.get_token().unwrap() != Token::Eof {
+            ranges.push((lexer1.token_start(), lexer1.token_end()));
+        }
+        assert_eq!(ranges, vec![(2, 5), (6, 9), (9, 10), (10, 13), (13, 14), (15, 16), (17, 18)]);
+        assert_eq!((lexer1.token_start(), lexer1.token_end()), (18, 18));
     }
 }
 
 
 
-use std::any::Any;
+use std::any::{Any, TypeId};
 use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+
+use intern::{Interner, Symbol};
 
 pub enum ExprASTKind {
     Number,
     Variable,
     Binary,
     Call,
+    Array,
+    Index,
+    Closure,
     Prototype,
+    Global,
     Function,
     Error,
     Empty,
 }
 
 // Abstract Syntax Tree(aka Parse Tree)
-pub trait ExprAST: Any + Debug {
+pub trait ExprAST: Any + Debug + Send + Sync {
     fn as_any(&self) -> &dyn Any;
     fn kind(&self) -> ExprASTKind;
+    // structural comparison, `Arc` pointer identity is ignored
+    fn eq_ast(&self, other: &dyn ExprAST) -> bool;
+    fn hash_ast(&self, state: &mut dyn Hasher);
+    // where the parser found the node, None for nodes built by hand
+    fn span(&self) -> Option<Span>;
+}
+impl PartialEq for dyn ExprAST {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_ast(other)
+    }
+}
+// NumberExprAST 按位比较, 所以比较满足自反性
+impl Eq for dyn ExprAST {}
+impl Hash for dyn ExprAST {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash_ast(state)
+    }
 }
 
 // macro automatic implement ExprAST for Structs
@@ -281,13 +1757,73 @@ macro_rules! impl_expr_ast {
                         "VariableExprAST" => ExprASTKind::Variable,
                         "BinaryExprAST" => ExprASTKind::Binary,
                         "CallExprAST" => ExprASTKind::Call,
+                        "ArrayExprAST" => ExprASTKind::Array,
+                        "IndexExprAST" => ExprASTKind::Index,
+                        "ClosureExprAST" => ExprASTKind::Closure,
                         "PrototypeAST" => ExprASTKind::Prototype,
+                        "GlobalAST" => ExprASTKind::Global,
                         "FunctionAST" => ExprASTKind::Function,
                         "ErrorAST" => ExprASTKind::Error,
                         "EmptyExprAST" => ExprASTKind::Empty,
                         _ => panic!("Unknown AST type"),
                     }
                 }
+                fn eq_ast(&self, other: &dyn ExprAST) -> bool {
+                    other
+                        .as_any()
+                        .downcast_ref::<$struct_name>()
+                        .is_some_and(|other| self == other)
+                }
+                fn hash_ast(&self, mut state: &mut dyn Hasher) {
+                    TypeId::of::<$struct_name>().hash(&mut state);
+                    self.hash(&mut state);
+                }
+                fn span(&self) -> Option<Span> {
+                    $struct_name::span(self)
+                }
+            }
+        )*
+    };
+}
+
+// 节点在源码中的范围等附加信息, 不参与结构比较和哈希
+#[derive(Clone, Default)]
+struct SourceInfo<T>(T);
+// Debug 输出写成 start..end
+impl Debug for SourceInfo<Option<Span>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(span) => write!(f, "{}", span),
+            None => write!(f, "None"),
+        }
+    }
+}
+impl Debug for SourceInfo<Vec<Span>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.iter().map(|span| SourceInfo(Some(*span)))).finish()
+    }
+}
+impl<T> PartialEq for SourceInfo<T> {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+impl<T> Hash for SourceInfo<T> {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+// 给有 span 字段的节点加上 with_span 和 span
+macro_rules! impl_node_span {
+    ($($struct_name:ident),*) => {
+        $(
+            impl $struct_name {
+                pub fn with_span(mut self, span: Span) -> Self {
+                    self.span = SourceInfo(Some(span));
+                    self
+                }
+                pub fn span(&self) -> Option<Span> {
+                    self.span.0
+                }
             }
         )*
     };
@@ -297,100 +1833,427 @@ macro_rules! impl_expr_ast {
 #[derive(Debug)]
 pub struct NumberExprAST {
     val: f64,
+    span: SourceInfo<Option<Span>>,
+}
+// 按位比较, 与 Hash 保持一致
+impl PartialEq for NumberExprAST {
+    fn eq(&self, other: &Self) -> bool {
+        self.val.to_bits() == other.val.to_bits()
+    }
+}
+impl Hash for NumberExprAST {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.val.to_bits().hash(state);
+    }
 }
 impl NumberExprAST {
     pub fn new(val: f64) -> Self {
-        NumberExprAST { val: val }
+        NumberExprAST {
+            val,
+            span: SourceInfo::default(),
+        }
+    }
+    pub fn val(&self) -> f64 {
+        self.val
     }
 }
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Hash)]
 pub struct VariableExprAST {
-    name: String,
+    name: Symbol,
+    span: SourceInfo<Option<Span>>,
 }
 impl VariableExprAST {
-    pub fn new(name: String) -> Self {
-        VariableExprAST { name: name }
+    pub fn new(name: impl Into<Symbol>) -> Self {
+        VariableExprAST {
+            name: name.into(),
+            span: SourceInfo::default(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+    pub fn symbol(&self) -> &Symbol {
+        &self.name
     }
 }
 
 #[derive(Debug)]
 pub struct BinaryExprAST {
     op: char,
-    lhs: Rc<dyn ExprAST>,
-    rhs: Rc<dyn ExprAST>,
+    lhs: Arc<dyn ExprAST>,
+    rhs: Arc<dyn ExprAST>,
+    span: SourceInfo<Option<Span>>,
 }
 impl BinaryExprAST {
-    pub fn new(op: char, lhs: Rc<dyn ExprAST>, rhs: Rc<dyn ExprAST>) -> BinaryExprAST {
+    pub fn new(op: char, lhs: Arc<dyn ExprAST>, rhs: Arc<dyn ExprAST>) -> BinaryExprAST {
         BinaryExprAST {
-            op: op,
-            lhs: lhs,
-            rhs: rhs,
+            op,
+            lhs,
+            rhs,
+            span: SourceInfo::default(),
         }
     }
+    pub fn op(&self) -> char {
+        self.op
+    }
+    pub fn lhs(&self) -> &Arc<dyn ExprAST> {
+        &self.lhs
+    }
+    pub fn rhs(&self) -> &Arc<dyn ExprAST> {
+        &self.rhs
+    }
 }
-#[derive(Debug)]
+// derive 无法直接比较 Arc<dyn ExprAST> 字段, 手动解引用比较
+impl PartialEq for BinaryExprAST {
+    fn eq(&self, other: &Self) -> bool {
+        self.op == other.op && *self.lhs == *other.lhs && *self.rhs == *other.rhs
+    }
+}
+impl Hash for BinaryExprAST {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.op.hash(state);
+        self.lhs.hash(state);
+        self.rhs.hash(state);
+    }
+}
+#[derive(Debug, PartialEq, Hash)]
 pub struct CallExprAST {
-    callee: String,
-    args: Vec<Rc<dyn ExprAST>>,
+    callee: Symbol,
+    args: Vec<Arc<dyn ExprAST>>,
+    span: SourceInfo<Option<Span>>,
+    callee_span: SourceInfo<Option<Span>>, // 函数名的范围
 }
 impl CallExprAST {
-    pub fn new(callee: String, args: Vec<Rc<dyn ExprAST>>) -> Self {
+    pub fn new(callee: impl Into<Symbol>, args: Vec<Arc<dyn ExprAST>>) -> Self {
         CallExprAST {
-            callee: callee,
-            args: args,
+            callee: callee.into(),
+            args,
+            span: SourceInfo::default(),
+            callee_span: SourceInfo::default(),
+        }
+    }
+    pub fn with_callee_span(mut self, span: Span) -> Self {
+        self.callee_span = SourceInfo(Some(span));
+        self
+    }
+    pub fn callee_span(&self) -> Option<Span> {
+        self.callee_span.0
+    }
+    pub fn callee(&self) -> &str {
+        self.callee.as_str()
+    }
+    pub fn callee_symbol(&self) -> &Symbol {
+        &self.callee
+    }
+    pub fn args(&self) -> &[Arc<dyn ExprAST>] {
+        &self.args
+    }
+}
+// 数组字面量 [a, b, c], 元素都是 double
+#[derive(Debug, PartialEq, Hash)]
+pub struct ArrayExprAST {
+    elements: Vec<Arc<dyn ExprAST>>,
+    span: SourceInfo<Option<Span>>,
+}
+impl ArrayExprAST {
+    pub fn new(elements: Vec<Arc<dyn ExprAST>>) -> Self {
+        ArrayExprAST {
+            elements,
+            span: SourceInfo::default(),
         }
     }
+    pub fn elements(&self) -> &[Arc<dyn ExprAST>] {
+        &self.elements
+    }
 }
+// 下标表达式 array[index]
 #[derive(Debug)]
+pub struct IndexExprAST {
+    array: Arc<dyn ExprAST>,
+    index: Arc<dyn ExprAST>,
+    span: SourceInfo<Option<Span>>,
+}
+impl IndexExprAST {
+    pub fn new(array: Arc<dyn ExprAST>, index: Arc<dyn ExprAST>) -> Self {
+        IndexExprAST {
+            array,
+            index,
+            span: SourceInfo::default(),
+        }
+    }
+    pub fn array(&self) -> &Arc<dyn ExprAST> {
+        &self.array
+    }
+    pub fn index(&self) -> &Arc<dyn ExprAST> {
+        &self.index
+    }
+}
+impl PartialEq for IndexExprAST {
+    fn eq(&self, other: &Self) -> bool {
+        *self.array == *other.array && *self.index == *other.index
+    }
+}
+impl Hash for IndexExprAST {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.array.hash(state);
+        self.index.hash(state);
+    }
+}
+// 静态类型; 没有标注时默认为 double
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Type {
+    #[default]
+    Double,
+    Bool,
+    Array, // double 数组, 长度由字面量决定
+}
+impl Type {
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "double" => Some(Type::Double),
+            "bool" => Some(Type::Bool),
+            "array" => Some(Type::Array),
+            _ => None,
+        }
+    }
+    // bool 可以隐式转换成 double (0 或 1), 反之不行
+    pub fn accepts(self, found: Type) -> bool {
+        self == found || (self == Type::Double && found == Type::Bool)
+    }
+}
+impl Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Double => write!(f, "double"),
+            Type::Bool => write!(f, "bool"),
+            Type::Array => write!(f, "array"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Hash)]
 pub struct PrototypeAST {
-    name: String,
-    args: Vec<String>,
+    name: Symbol,
+    args: Vec<Symbol>,
+    arg_types: Vec<Type>,
+    return_type: Type,
+    varargs: bool, // 固定参数之后还可以传任意多个 double, 只有 extern 可以
+    span: SourceInfo<Option<Span>>,
+    arg_spans: SourceInfo<Vec<Span>>, // 每个参数名的范围
 }
 impl PrototypeAST {
-    pub fn new(name: String, args: Vec<String>) -> PrototypeAST {
+    // 参数和返回值都是 double
+    pub fn new(name: impl Into<Symbol>, args: Vec<Symbol>) -> PrototypeAST {
+        let arg_types = vec![Type::Double; args.len()];
+        PrototypeAST::with_types(name, args, arg_types, Type::Double)
+    }
+    pub fn with_types(
+        name: impl Into<Symbol>,
+        args: Vec<Symbol>,
+        arg_types: Vec<Type>,
+        return_type: Type,
+    ) -> PrototypeAST {
+        assert_eq!(args.len(), arg_types.len());
         PrototypeAST {
-            name: name,
-            args: args,
+            name: name.into(),
+            args,
+            arg_types,
+            return_type,
+            varargs: false,
+            span: SourceInfo::default(),
+            arg_spans: SourceInfo::default(),
         }
     }
+    pub fn with_arg_spans(mut self, arg_spans: Vec<Span>) -> PrototypeAST {
+        assert_eq!(self.args.len(), arg_spans.len());
+        self.arg_spans = SourceInfo(arg_spans);
+        self
+    }
+    // 第 i 个参数名的范围
+    pub fn arg_span(&self, i: usize) -> Option<Span> {
+        self.arg_spans.0.get(i).copied()
+    }
+    pub fn with_varargs(mut self, varargs: bool) -> PrototypeAST {
+        self.varargs = varargs;
+        self
+    }
+    pub fn is_varargs(&self) -> bool {
+        self.varargs
+    }
+    pub fn arg_types(&self) -> &[Type] {
+        &self.arg_types
+    }
+    pub fn return_type(&self) -> Type {
+        self.return_type
+    }
+    // 有不是 double 的参数或返回值
+    pub fn is_typed(&self) -> bool {
+        self.return_type != Type::Double || self.arg_types.iter().any(|ty| *ty != Type::Double)
+    }
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+    pub fn symbol(&self) -> &Symbol {
+        &self.name
+    }
+    pub fn args(&self) -> &[Symbol] {
+        &self.args
+    }
 }
 #[derive(Debug)]
 pub struct FunctionAST {
-    proto: Rc<PrototypeAST>,
-    body: Rc<dyn ExprAST>,
+    proto: Arc<PrototypeAST>,
+    body: Arc<dyn ExprAST>,
 }
 impl FunctionAST {
-    pub fn new(proto: Rc<PrototypeAST>, body: Rc<dyn ExprAST>) -> Self {
+    pub fn new(proto: Arc<PrototypeAST>, body: Arc<dyn ExprAST>) -> Self {
         FunctionAST {
-            proto: proto,
-            body: body,
+            proto,
+            body,
         }
     }
+    pub fn proto(&self) -> &Arc<PrototypeAST> {
+        &self.proto
+    }
+    pub fn body(&self) -> &Arc<dyn ExprAST> {
+        &self.body
+    }
+    // 从原型到函数体结束
+    pub fn span(&self) -> Option<Span> {
+        Some(Span::new(self.proto.span()?.start, self.body.span()?.end))
+    }
+}
+impl PartialEq for FunctionAST {
+    fn eq(&self, other: &Self) -> bool {
+        self.proto == other.proto && *self.body == *other.body
+    }
+}
+impl Hash for FunctionAST {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.proto.hash(state);
+        self.body.hash(state);
+    }
 }
 
-// error-handling node
+// 全局变量 global name = init, 在它之后定义的函数都可以读取
+#[derive(Debug)]
+pub struct GlobalAST {
+    name: Symbol,
+    init: Arc<dyn ExprAST>,
+    span: SourceInfo<Option<Span>>,
+}
+impl GlobalAST {
+    pub fn new(name: impl Into<Symbol>, init: Arc<dyn ExprAST>) -> Self {
+        GlobalAST {
+            name: name.into(),
+            init,
+            span: SourceInfo::default(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+    pub fn symbol(&self) -> &Symbol {
+        &self.name
+    }
+    pub fn init(&self) -> &Arc<dyn ExprAST> {
+        &self.init
+    }
+}
+impl PartialEq for GlobalAST {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && *self.init == *other.init
+    }
+}
+impl Hash for GlobalAST {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.init.hash(state);
+    }
+}
+
+// 局部函数 def name(args) body in expr: 函数只在 expr 和它自己的函数体中可见,
+// 函数体可以读取外层的参数, 按值捕获
 #[derive(Debug)]
+pub struct ClosureExprAST {
+    function: Arc<FunctionAST>,
+    body: Arc<dyn ExprAST>,
+    span: SourceInfo<Option<Span>>,
+}
+impl ClosureExprAST {
+    pub fn new(function: Arc<FunctionAST>, body: Arc<dyn ExprAST>) -> Self {
+        ClosureExprAST {
+            function,
+            body,
+            span: SourceInfo::default(),
+        }
+    }
+    pub fn function(&self) -> &Arc<FunctionAST> {
+        &self.function
+    }
+    // in 之后的表达式, 它的值就是整个表达式的值
+    pub fn body(&self) -> &Arc<dyn ExprAST> {
+        &self.body
+    }
+}
+impl PartialEq for ClosureExprAST {
+    fn eq(&self, other: &Self) -> bool {
+        self.function == other.function && *self.body == *other.body
+    }
+}
+impl Hash for ClosureExprAST {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.function.hash(state);
+        self.body.hash(state);
+    }
+}
+
+// error-handling node
+#[derive(Debug, PartialEq, Hash)]
 pub struct ErrorAST {
     error: ParseError,
 }
 impl ErrorAST {
     pub fn new(error: ParseError) -> Self {
-        Self { error: error }
+        Self { error }
     }
     pub fn get_error(&self) -> &ParseError {
         &self.error
     }
+    pub fn span(&self) -> Option<Span> {
+        self.error.span()
+    }
 }
 
 // None node
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Hash)]
 pub struct EmptyExprAST;
+impl EmptyExprAST {
+    pub fn span(&self) -> Option<Span> {
+        None
+    }
+}
+impl_node_span!(
+    NumberExprAST,
+    VariableExprAST,
+    BinaryExprAST,
+    CallExprAST,
+    ArrayExprAST,
+    IndexExprAST,
+    ClosureExprAST,
+    PrototypeAST,
+    GlobalAST
+);
 impl_expr_ast!(
     NumberExprAST,
     VariableExprAST,
     BinaryExprAST,
     CallExprAST,
+    ArrayExprAST,
+    IndexExprAST,
+    ClosureExprAST,
     PrototypeAST,
+    GlobalAST,
     FunctionAST,
     ErrorAST,
     EmptyExprAST
@@ -399,11 +2262,14 @@ impl_expr_ast!(
 use std::error::Error as StdError;
 use std::fmt;
 use std::fmt::Display;
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Hash)]
 pub enum ParseError {
-    LexerError(String),
+    LexerError(LexError),
     SyntaxError(String),
-    UnexpectedToken(Token, &'static str),
+    // 遇到的 token, 此处合法的全部 token, 遇到的 token 的位置
+    UnexpectedToken(Token, Vec<Token>, Span),
+    // 此处合法的全部 token, 输入结束的位置
+    UnexpectedEof(Vec<Token>, Span),
     GeneralError(String),
 }
 impl Display for ParseError {
@@ -411,92 +2277,883 @@ impl Display for ParseError {
         match self {
             ParseError::LexerError(msg) => write!(f, "Lexer error:{}", msg),
             ParseError::SyntaxError(msg) => write!(f, "Syntax error:{}", msg),
-            ParseError::UnexpectedToken(tok, expected) => {
-                write!(f, "unexpected token:{:?}, expected {}", tok, expected)
+            ParseError::UnexpectedToken(tok, expected, span) => {
+                write!(f, "{}, got {} at {}", describe_expected(expected), describe_token(tok), span)
+            }
+            ParseError::UnexpectedEof(expected, _) => {
+                write!(f, "unexpected end of input, {}", describe_expected(expected))
             }
             ParseError::GeneralError(msg) => write!(f, "error:{}", msg),
         }
     }
 }
-impl StdError for ParseError {}
-pub fn syntax_error<T>(msg: &str) -> Result<T, ParseError> {
-    Err(ParseError::SyntaxError(msg.to_string()))
-}
-pub fn unexpected_token<T>(tok: Token, expected: &'static str) -> Result<T, ParseError> {
-    Err(ParseError::UnexpectedToken(tok, expected))
-}
-
-#[derive(Debug)]
-pub struct ASTParser<R: Read> {
-    lexer: Lexer<R>,
-    ast: Rc<dyn ExprAST>,
-    curtok: Token,
-}
-impl<R: Read> ASTParser<R> {
-    pub fn new(lexer:Lexer<R>) -> Self {
-        let temp_tok = lexer.cur_tok;
-        if lexer.last_char != CharState::NotInitailized {
-            panic!("lexer  has been used");
+impl ParseError {
+    // 稳定的错误码, 词法错误沿用 LexError 的编号
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::LexerError(error) => error.code(),
+            ParseError::SyntaxError(_) => "K0101",
+            ParseError::UnexpectedToken(..) => "K0102",
+            ParseError::UnexpectedEof(..) => "K0103",
+            ParseError::GeneralError(_) => "K0104",
+        }
+    }
+    // 有位置信息的错误的字节范围
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ParseError::LexerError(error) => Some(error.span()),
+            ParseError::UnexpectedToken(_, _, span) | ParseError::UnexpectedEof(_, span) => Some(*span),
+            ParseError::SyntaxError(_) | ParseError::GeneralError(_) => None,
+        }
+    }
+    // true when more input could still complete the construct
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, ParseError::UnexpectedEof(..))
+    }
+    // 把错误中的位置换成 f(位置), 例如从整个程序的偏移换成文件内的偏移
+    pub(crate) fn map_span(self, f: impl Fn(Span) -> Span) -> ParseError {
+        match self {
+            ParseError::LexerError(error) => ParseError::LexerError(error.map_span(f)),
+            ParseError::UnexpectedToken(tok, expected, span) => {
+                ParseError::UnexpectedToken(tok, expected, f(span))
+            }
+            ParseError::UnexpectedEof(expected, span) => ParseError::UnexpectedEof(expected, f(span)),
+            error => error,
+        }
+    }
+}
+impl StdError for ParseError {}
+
+// ')' 和 '<' 这样的符号加引号, 其它 token 用名字, 例如 Identifier
+fn describe_token(tok: &Token) -> String {
+    match tok {
+        Token::Unknown(c) => format!("'{}'", c),
+        Token::Keyword(keyword) => format!("'{}'", keyword.spelling()),
+        tok => match tok.spelling() {
+            Some(spelling) => format!("'{}'", spelling),
+            None => format!("{:?}", tok),
+        },
+    }
+}
+// expected ')' 或 expected one of ')', ','
+fn describe_expected(expected: &[Token]) -> String {
+    let tokens: Vec<String> = expected.iter().map(describe_token).collect();
+    match tokens.as_slice() {
+        [tok] => format!("expected {}", tok),
+        tokens => format!("expected one of {}", tokens.join(", ")),
+    }
+}
+
+pub fn syntax_error<T>(msg: &str) -> Result<T, ParseError> {
+    Err(ParseError::SyntaxError(msg.to_string()))
+}
+pub fn unexpected_token<T>(tok: Token, expected: Vec<Token>, span: Span) -> Result<T, ParseError> {
+    Err(ParseError::UnexpectedToken(tok, expected, span))
+}
+
+// How `ASTParser` builds what it parses. `ArcBuilder`, the default, makes
+// the `ExprAST` nodes above, each in its own Arc; `arena::ArenaBuilder`
+// stores the expressions of a whole program in one `AstArena` instead.
+// A failed expression is an error node, like `ErrorAST`, so the parser
+// can give up at any depth and report the error for the whole item.
+pub trait AstBuilder {
+    type Expr;
+    type Function;
+    type Global;
+    type Item;
+
+    fn number(&mut self, val: f64, span: Span) -> Self::Expr;
+    fn variable(&mut self, name: Symbol, span: Span) -> Self::Expr;
+    fn binary(&mut self, op: char, lhs: Self::Expr, rhs: Self::Expr, span: Span) -> Self::Expr;
+    fn call(&mut self, callee: Symbol, args: Vec<Self::Expr>, span: Span, callee_span: Span) -> Self::Expr;
+    fn array(&mut self, elements: Vec<Self::Expr>, span: Span) -> Self::Expr;
+    fn index(&mut self, array: Self::Expr, index: Self::Expr, span: Span) -> Self::Expr;
+    // def proto function_body in body
+    fn closure(&mut self, proto: Arc<PrototypeAST>, function_body: Self::Expr, body: Self::Expr, span: Span) -> Self::Expr;
+    fn error(&mut self, error: ParseError) -> Self::Expr;
+    // 是错误节点时返回其中的错误
+    fn as_error<'a>(&'a self, expr: &'a Self::Expr) -> Option<&'a ParseError>;
+    fn span(&self, expr: &Self::Expr) -> Option<Span>;
+
+    fn function(&mut self, proto: Arc<PrototypeAST>, body: Self::Expr) -> Self::Function;
+    fn global(&mut self, name: Symbol, init: Self::Expr, span: Span) -> Self::Global;
+
+    fn def_item(function: Self::Function) -> Self::Item;
+    fn extern_item(proto: Arc<PrototypeAST>) -> Self::Item;
+    fn global_item(global: Self::Global) -> Self::Item;
+    fn expr_item(function: Self::Function) -> Self::Item;
+}
+
+// 默认的 builder, 每个节点单独分配一个 Arc
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArcBuilder;
+impl AstBuilder for ArcBuilder {
+    type Expr = Arc<dyn ExprAST>;
+    type Function = Arc<FunctionAST>;
+    type Global = Arc<GlobalAST>;
+    type Item = TopLevelItem;
+
+    fn number(&mut self, val: f64, span: Span) -> Self::Expr {
+        Arc::new(NumberExprAST::new(val).with_span(span))
+    }
+    fn variable(&mut self, name: Symbol, span: Span) -> Self::Expr {
+        Arc::new(VariableExprAST::new(name).with_span(span))
+    }
+    fn binary(&mut self, op: char, lhs: Self::Expr, rhs: Self::Expr, span: Span) -> Self::Expr {
+        Arc::new(BinaryExprAST::new(op, lhs, rhs).with_span(span))
+    }
+    fn call(&mut self, callee: Symbol, args: Vec<Self::Expr>, span: Span, callee_span: Span) -> Self::Expr {
+        let call = CallExprAST::new(callee, args).with_span(span);
+        Arc::new(call.with_callee_span(callee_span))
+    }
+    fn array(&mut self, elements: Vec<Self::Expr>, span: Span) -> Self::Expr {
+        Arc::new(ArrayExprAST::new(elements).with_span(span))
+    }
+    fn index(&mut self, array: Self::Expr, index: Self::Expr, span: Span) -> Self::Expr {
+        Arc::new(IndexExprAST::new(array, index).with_span(span))
+    }
+    fn closure(&mut self, proto: Arc<PrototypeAST>, function_body: Self::Expr, body: Self::Expr, span: Span) -> Self::Expr {
+        let function = Arc::new(FunctionAST::new(proto, function_body));
+        Arc::new(ClosureExprAST::new(function, body).with_span(span))
+    }
+    fn error(&mut self, error: ParseError) -> Self::Expr {
+        Arc::new(ErrorAST::new(error))
+    }
+    fn as_error<'a>(&'a self, expr: &'a Self::Expr) -> Option<&'a ParseError> {
+        expr.as_any().downcast_ref::<ErrorAST>().map(ErrorAST::get_error)
+    }
+    fn span(&self, expr: &Self::Expr) -> Option<Span> {
+        expr.span()
+    }
+
+    fn function(&mut self, proto: Arc<PrototypeAST>, body: Self::Expr) -> Self::Function {
+        Arc::new(FunctionAST::new(proto, body))
+    }
+    fn global(&mut self, name: Symbol, init: Self::Expr, span: Span) -> Self::Global {
+        Arc::new(GlobalAST::new(name, init).with_span(span))
+    }
+
+    fn def_item(function: Self::Function) -> Self::Item {
+        TopLevelItem::Def(function)
+    }
+    fn extern_item(proto: Arc<PrototypeAST>) -> Self::Item {
+        TopLevelItem::Extern(proto)
+    }
+    fn global_item(global: Self::Global) -> Self::Item {
+        TopLevelItem::Global(global)
+    }
+    fn expr_item(function: Self::Function) -> Self::Item {
+        TopLevelItem::Expr(function)
+    }
+}
+
+#[derive(Debug)]
+pub struct ASTParser<R: Source, B: AstBuilder = ArcBuilder> {
+    lexer: Lexer<R>,
+    curtok: Token,
+    anon_count: usize, // 已生成的匿名函数个数
+    prev_end: usize,    // 上一个 token 的结束偏移
+    interner: Interner, // 解析出的名字
+    missing_separator: Option<(ParseError, Span)>, // 上一项之后缺少的 ';', 下次调用 parse_next_item 时报告
+    builder: B,
+}
+impl<R: Source> ASTParser<R> {
+    pub fn new(lexer:Lexer<R>) -> Self {
+        ASTParser::with_builder(lexer, ArcBuilder)
+    }
+
+    // program ::= (top (';' top)*)? ';'?
+    // 出错后跳过出错的 token 继续解析, 收集全部错误
+    pub fn parse_program(&mut self) -> Result<Program, Vec<ParseError>> {
+        if self.curtok == Token::None {
+            self.update_token();
+        }
+        let mut items = Vec::new();
+        let mut errors = Vec::new();
+        while let Some((item, _)) = self.parse_next_item() {
+            match item {
+                Ok(item) => items.push(item),
+                Err(error) => errors.push(error),
+            }
+        }
+        if errors.is_empty() {
+            Ok(Program::with_interner(items, self.interner.clone()))
+        } else {
+            Err(errors)
+        }
+    }
+}
+impl<R: Source, B: AstBuilder> ASTParser<R, B> {
+    pub fn with_builder(lexer: Lexer<R>, builder: B) -> Self {
+        let temp_tok = lexer.cur_tok;
+        if lexer.last_char != CharState::NotInitailized {
+            panic!("lexer  has been used");
         }
         ASTParser {
-            lexer: lexer,
-            ast: Rc::new(EmptyExprAST),
+            lexer,
             curtok: temp_tok,
+            anon_count: 0,
+            prev_end: 0,
+            interner: Interner::new(),
+            missing_separator: None,
+            builder,
         }
     }
     pub fn update_token(&mut self){
+        self.prev_end = self.lexer.token_end();
         self.lexer.update_token();
         self.curtok = self.lexer.cur_tok;
     }
-    pub fn parse_expression() -> Rc<dyn ExprAST>{
-        todo!()
+    // Runs `parse` speculatively: on error the parser is put back where it
+    // was, as if nothing had been read, so another production can be tried.
+    pub fn try_parse<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        let checkpoint = self.lexer.checkpoint();
+        let (prev_end, anon_count) = (self.prev_end, self.anon_count);
+        let result = parse(self);
+        if result.is_ok() {
+            self.lexer.commit(checkpoint);
+        } else {
+            self.lexer.restore(checkpoint);
+            self.curtok = self.lexer.cur_tok;
+            self.prev_end = prev_end;
+            self.anon_count = anon_count;
+        }
+        result
     }
-    // 调用主函数
-    // 已经调用updae_lexer 迭代得到当前token为原子表达式的时候调用
-    pub fn parse_primary(&mut self) -> Rc<dyn ExprAST>{
+    // 当前token为 Eof 时说明输入在结构中途结束, 交互式输入可以继续读取下一行
+    // expected 是当前位置合法的全部 token
+    fn unexpected(&self, expected: &[Token]) -> ParseError {
+        // 读取失败或无效的 UTF-8 比 "unexpected token" 更能说明问题
+        if self.lexer.error().is_some() {
+            return self.lexer_error();
+        }
         match self.curtok {
-            Token::Number => self.parse_number_expr(),
-            _ => unreachable!()
-        }
-
-    }
-    
-    pub fn parse_identifierExpr(&mut self)->Rc<dyn ExprAST>{
-        let name = self.lexer.identifier_str.clone();
-        self.update_token();
-        match self.curtok{
-            Token::Char('(') => Rc::new(VariableExprAST::new(Name)),
-            _ => {
-                self.update_token();
-                let args: vec<Rc<dyn ExprAST>>;
-                if self.curtok != ')'  {
-                    while(1){
-                        
-                        let arg = self.parse_expression();
-                        if (self.curtok == ')') break;
-                        if (self.curtok != ',')
+            Token::Eof => ParseError::UnexpectedEof(expected.to_vec(), self.lexer.token_span()),
+            tok => ParseError::UnexpectedToken(tok, expected.to_vec(), self.lexer.token_span()),
+        }
+    }
+    fn error_ast(&mut self, expected: &[Token]) -> B::Expr {
+        let error = self.unexpected(expected);
+        self.builder.error(error)
+    }
+    fn is_error(&self, expr: &B::Expr) -> bool {
+        self.builder.as_error(expr).is_some()
+    }
 
-                    }
+    // binary operator precedence, -1 for tokens that are not binary operators
+    fn get_tok_precedence(&self) -> i32 {
+        match self.curtok {
+            Token::Op(op) => op.precedence(),
+            _ => -1,
+        }
+    }
+
+    // expression ::= primary binoprhs
+    pub fn parse_expression(&mut self) -> B::Expr {
+        let lhs = self.parse_primary();
+        if self.is_error(&lhs) {
+            return lhs;
+        }
+        self.parse_bin_op_rhs(0, lhs)
+    }
+
+    // binoprhs ::= (binop primary)*
+    pub fn parse_bin_op_rhs(&mut self, expr_prec: i32, mut lhs: B::Expr) -> B::Expr {
+        loop {
+            let tok_prec = self.get_tok_precedence();
+            if tok_prec < expr_prec {
+                return lhs;
+            }
+            let Token::Op(op) = self.curtok else {
+                unreachable!()
+            };
+            let start = self.builder.span(&lhs).map_or(self.lexer.token_start(), |span| span.start);
+            self.update_token(); // eat binop
 
+            let mut rhs = self.parse_primary();
+            if self.is_error(&rhs) {
+                return rhs;
+            }
+            // 下一个运算符优先级更高时, 先让它和 rhs 结合
+            let next_prec = self.get_tok_precedence();
+            if tok_prec < next_prec {
+                rhs = self.parse_bin_op_rhs(tok_prec + 1, rhs);
+                if self.is_error(&rhs) {
+                    return rhs;
                 }
+            }
+            let span = Span::new(start, self.prev_end);
+            lhs = self.builder.binary(op.as_char(), lhs, rhs, span);
+        }
+    }
+
+    // 调用主函数
+    // 已经调用updae_lexer 迭代得到当前token为原子表达式的时候调用
+    // primary ::= (identifierexpr | numberexpr | parenexpr | arrayexpr | closureexpr) ('[' expression ']')*
+    pub fn parse_primary(&mut self) -> B::Expr{
+        let mut expr = match self.curtok {
+            Token::Identifier if self.lexer.error().is_some() => {
+                let error = self.lexer_error();
+                self.builder.error(error)
+            }
+            // 这里的上下文关键字是变量名或函数名
+            Token::Identifier | Token::Keyword(_) => self.parse_identifier_expr(),
+            Token::Number => self.parse_number_expr(),
+            Token::LParen => self.parse_paren_expr(),
+            Token::LBracket => self.parse_array_expr(),
+            Token::Def => self.parse_closure_expr(),
+            _ => self.error_ast(&EXPRESSION_START),
+        };
+        while !self.is_error(&expr) && self.curtok == Token::LBracket {
+            expr = self.parse_index_expr(expr);
+        }
+        expr
+    }
 
+    // arrayexpr ::= '[' (expression (',' expression)*)? ']'
+    pub fn parse_array_expr(&mut self) -> B::Expr {
+        let start = self.lexer.token_start();
+        self.update_token(); // eat '['
+        let mut elements = Vec::new();
+        if self.curtok != Token::RBracket {
+            loop {
+                let element = self.parse_expression();
+                if self.is_error(&element) {
+                    return element;
+                }
+                elements.push(element);
+                if self.curtok == Token::RBracket {
+                    break;
+                }
+                if self.curtok != Token::Comma {
+                    return self.error_ast(&after_expression(&[Token::RBracket, Token::Comma]));
+                }
+                self.update_token(); // eat ','
             }
         }
+        self.update_token(); // eat ']'
+        let span = Span::new(start, self.prev_end);
+        self.builder.array(elements, span)
+    }
+
+    // closureexpr ::= 'def' prototype expression 'in' expression
+    pub fn parse_closure_expr(&mut self) -> B::Expr {
+        let start = self.lexer.token_start();
+        self.update_token(); // eat def
+        let proto = match self.parse_prototype() {
+            Ok(proto) => proto,
+            Err(error) => return self.builder.error(error),
+        };
+        let function_body = self.parse_expression();
+        if self.is_error(&function_body) {
+            return function_body;
+        }
+        if self.curtok != Token::Keyword(Keyword::In) {
+            return self.error_ast(&after_expression(&[Token::Keyword(Keyword::In)]));
+        }
+        self.update_token(); // eat in
+        let body = self.parse_expression();
+        if self.is_error(&body) {
+            return body;
+        }
+        let span = Span::new(start, self.prev_end);
+        self.builder.closure(proto, function_body, body, span)
+    }
+
+    // 当前 token 为 '[' 时调用, 解析 array 的下标
+    pub fn parse_index_expr(&mut self, array: B::Expr) -> B::Expr {
+        let start = self.builder.span(&array).map_or(self.lexer.token_start(), |span| span.start);
+        self.update_token(); // eat '['
+        let index = self.parse_expression();
+        if self.is_error(&index) {
+            return index;
+        }
+        if self.curtok != Token::RBracket {
+            return self.error_ast(&after_expression(&[Token::RBracket]));
+        }
+        self.update_token(); // eat ']'
+        let span = Span::new(start, self.prev_end);
+        self.builder.index(array, index, span)
+    }
+
+    // parenexpr ::= '(' expression ')'
+    pub fn parse_paren_expr(&mut self) -> B::Expr {
+        self.update_token(); // eat '('
+        let expr = self.parse_expression();
+        if self.is_error(&expr) {
+            return expr;
+        }
+        if self.curtok != Token::RParen {
+            return self.error_ast(&after_expression(&[Token::RParen]));
+        }
+        self.update_token(); // eat ')'
+        expr
+    }
+
+    // 当前token为 identifier 时调用, 解析变量引用或函数调用
+    pub fn parse_identifier_expr(&mut self) -> B::Expr {
+        let name_span = self.lexer.token_span();
+        let name = self.interner.intern(self.lexer.identifier_text());
+        self.update_token(); // eat identifier
+        if self.curtok != Token::LParen {
+            return self.builder.variable(name, name_span);
+        }
 
+        self.update_token(); // eat '('
+        let mut args = Vec::new();
+        if self.curtok != Token::RParen {
+            loop {
+                let arg = self.parse_expression();
+                if self.is_error(&arg) {
+                    return arg;
+                }
+                args.push(arg);
+                if self.curtok == Token::RParen {
+                    break;
+                }
+                if self.curtok != Token::Comma {
+                    return self.error_ast(&after_expression(&[Token::RParen, Token::Comma]));
+                }
+                self.update_token(); // eat ','
+            }
+        }
+        self.update_token(); // eat ')'
+        let span = Span::new(name_span.start, self.prev_end);
+        self.builder.call(name, args, span, name_span)
     }
     // 已经调用lexer.update_token 迭代得到当前token为 number时调用
-    pub fn parse_number_expr(&mut self) -> Rc<dyn ExprAST> {
+    pub fn parse_number_expr(&mut self) -> B::Expr {
         match self.lexer.num_val {
             Some(num_val) => {
-                Rc::new(NumberExprAST::new(num_val))
+                let span = self.lexer.token_span();
+                self.update_token(); // eat number
+                self.builder.number(num_val, span)
             }
-            None => Rc::new(ErrorAST::new(ParseError::LexerError(
-                "Get a number token but the num_val has no number".to_string(),
-            ))),
+            None => {
+                let error = self.lexer_error();
+                self.builder.error(error)
+            }
+        }
+    }
+
+    // 只在当前 token 有词法错误时调用
+    fn lexer_error(&self) -> ParseError {
+        let error = self.lexer.error().expect("the current token has no lexer error");
+        ParseError::LexerError(error.clone())
+    }
+
+    // type ::= 'double' | 'bool' | 'array'
+    fn parse_type(&mut self) -> Result<Type, ParseError> {
+        if !self.curtok.is_name() {
+            return Err(self.unexpected(&[Token::Identifier]));
+        }
+        let ty = Type::from_name(self.lexer.identifier_text()).ok_or_else(|| {
+            ParseError::SyntaxError(format!("unknown type `{}`", self.lexer.identifier_text()))
+        })?;
+        self.update_token(); // eat type
+        Ok(ty)
+    }
+
+    // prototype ::= id '(' (id (':' type)? ','?)* ')' ('->' type)?
+    // 没有标注的参数和返回值是 double
+    pub fn parse_prototype(&mut self) -> Result<Arc<PrototypeAST>, ParseError> {
+        self.parse_signature(false)
+    }
+
+    // 同 prototype, allow_varargs 为 true 时 ')' 之前还可以有 '...'
+    fn parse_signature(&mut self, allow_varargs: bool) -> Result<Arc<PrototypeAST>, ParseError> {
+        if !self.curtok.is_name() {
+            return Err(self.unexpected(&[Token::Identifier]));
+        }
+        if self.lexer.error().is_some() {
+            return Err(self.lexer_error());
+        }
+        let start = self.lexer.token_start();
+        let name = self.interner.intern(self.lexer.identifier_text());
+        self.update_token(); // eat name
+
+        if self.curtok != Token::LParen {
+            return Err(self.unexpected(&[Token::LParen]));
+        }
+        let mut args = Vec::new();
+        let mut arg_spans = Vec::new();
+        let mut arg_types = Vec::new();
+        self.update_token(); // eat '('
+        let mut expected = vec![Token::Identifier, Token::RParen];
+        while self.curtok.is_name() {
+            if self.lexer.error().is_some() {
+                return Err(self.lexer_error());
+            }
+            args.push(self.interner.intern(self.lexer.identifier_text()));
+            arg_spans.push(self.lexer.token_span());
+            self.update_token();
+            expected = vec![Token::Identifier, Token::Comma, Token::RParen];
+            let ty = if self.curtok == Token::Colon {
+                self.update_token(); // eat ':'
+                self.parse_type()?
+            } else {
+                expected.insert(1, Token::Colon);
+                Type::Double
+            };
+            arg_types.push(ty);
+            if self.curtok == Token::Comma {
+                self.update_token(); // eat ','
+                expected = vec![Token::Identifier, Token::RParen];
+            }
+        }
+        let mut varargs = false;
+        if allow_varargs && self.curtok == Token::Ellipsis {
+            self.update_token(); // eat '...'
+            varargs = true;
+            expected = vec![Token::RParen];
+        } else if allow_varargs {
+            expected.insert(expected.len() - 1, Token::Ellipsis);
+        }
+        if self.curtok != Token::RParen {
+            return Err(self.unexpected(&expected));
+        }
+        self.update_token(); // eat ')'
+
+        let mut return_type = Type::Double;
+        if self.curtok == Token::Arrow {
+            self.update_token(); // eat '->'
+            return_type = self.parse_type()?;
+        }
+        let proto = PrototypeAST::with_types(name, args, arg_types, return_type);
+        let span = Span::new(start, self.prev_end);
+        let proto = proto.with_varargs(varargs).with_span(span);
+        Ok(Arc::new(proto.with_arg_spans(arg_spans)))
+    }
+
+    // definition ::= 'def' prototype expression
+    pub fn parse_definition(&mut self) -> Result<B::Function, ParseError> {
+        self.update_token(); // eat def
+        let proto = self.parse_prototype()?;
+        let body = self.parse_expression();
+        if let Some(error) = self.builder.as_error(&body) {
+            return Err(error.clone());
+        }
+        Ok(self.builder.function(proto, body))
+    }
+
+    // external ::= 'extern' id '(' (id (':' type)? ','?)* '...'? ')' ('->' type)?
+    pub fn parse_extern(&mut self) -> Result<Arc<PrototypeAST>, ParseError> {
+        self.update_token(); // eat extern
+        self.parse_signature(true)
+    }
+
+    // global ::= 'global' identifier '=' expression
+    pub fn parse_global(&mut self) -> Result<B::Global, ParseError> {
+        let start = self.lexer.token_start();
+        let name = self.parse_global_name()?;
+        let init = self.parse_expression();
+        if let Some(error) = self.builder.as_error(&init) {
+            return Err(error.clone());
+        }
+        let span = Span::new(start, self.prev_end);
+        Ok(self.builder.global(name, init, span))
+    }
+
+    // 解析 'global' identifier '=', 返回变量名
+    fn parse_global_name(&mut self) -> Result<Symbol, ParseError> {
+        self.update_token(); // eat global
+        if !self.curtok.is_name() {
+            return Err(self.unexpected(&[Token::Identifier]));
+        }
+        if self.lexer.error().is_some() {
+            return Err(self.lexer_error());
+        }
+        let name = self.interner.intern(self.lexer.identifier_text());
+        self.update_token(); // eat identifier
+        if self.curtok != Token::Equals {
+            return Err(self.unexpected(&[Token::Equals]));
+        }
+        self.update_token(); // eat '='
+        Ok(name)
+    }
+
+    // toplevelexpr ::= expression
+    // 包装成无参数的匿名函数 __anon_exprN, N 按出现顺序编号
+    pub fn parse_top_level_expr(&mut self) -> Result<B::Function, ParseError> {
+        let body = self.parse_expression();
+        if let Some(error) = self.builder.as_error(&body) {
+            return Err(error.clone());
+        }
+        let name = format!("__anon_expr{}", self.anon_count);
+        self.anon_count += 1;
+        let proto = Arc::new(PrototypeAST::new(name, Vec::new()));
+        Ok(self.builder.function(proto, body))
+    }
+
+    // One step of `parse_program`: the next item or the next error, with
+    // where it is in the source. After an error the offending token is
+    // skipped. Adjacent items must be separated by ';': an item followed by
+    // anything else is still returned, and the missing separator is
+    // reported by the next call, after which parsing goes on.
+    pub fn parse_next_item(&mut self) -> Option<(Result<B::Item, ParseError>, Span)> {
+        if let Some((error, span)) = self.missing_separator.take() {
+            return Some((Err(error), span));
+        }
+        while self.curtok == Token::Semicolon {
+            self.update_token();
+        }
+        let extern_item = self.curtok == Token::Extern;
+        let (item, span) = self.parse_top_level_with_span()?;
+        self.end_item(&item, || after_item(extern_item));
+        Some((item, span))
+    }
+
+    // Like `parse_next_item`, for the `import name` items only the loader
+    // accepts. Returns None unless the next item is an import; a missing
+    // separator left by the previous item is then reported first.
+    pub fn parse_next_import(&mut self) -> Option<(Result<String, ParseError>, Span)> {
+        if self.missing_separator.is_some() {
+            return None;
+        }
+        while self.curtok == Token::Semicolon {
+            self.update_token();
+        }
+        if !(self.curtok == Token::Keyword(Keyword::Import) && self.keyword_applies()) {
+            return None;
+        }
+        let start = self.lexer.token_start();
+        let import = self.parse_import();
+        let span = match import {
+            Ok(_) => Span::new(start, self.prev_end),
+            Err(_) => self.lexer.token_span(),
+        };
+        self.end_item(&import, || vec![Token::Semicolon, Token::Eof]);
+        Some((import, span))
+    }
+
+    // 一项解析完之后: 出错时跳过出错的 token, 否则后面必须是分隔符,
+    // 缺少分隔符的错误留到下一次报告
+    fn end_item<T>(&mut self, item: &Result<T, ParseError>, expected: impl FnOnce() -> Vec<Token>) {
+        match item {
+            Err(_) => self.update_token(),
+            Ok(_) if !matches!(self.curtok, Token::Semicolon | Token::Eof) => {
+                let error = self.unexpected(&expected());
+                self.missing_separator = Some((error, self.lexer.token_span()));
+            }
+            Ok(_) => {}
+        }
+    }
+
+    // Like `parse_top_level`, but also returns where the item is in the
+    // source: the whole item on success, the offending token on error.
+    pub fn parse_top_level_with_span(&mut self) -> Option<(Result<B::Item, ParseError>, Span)> {
+        while self.curtok == Token::Semicolon {
+            self.update_token();
+        }
+        let start = self.lexer.token_start();
+        let item = self.parse_top_level()?;
+        let span = match item {
+            Ok(_) => Span::new(start, self.prev_end),
+            Err(_) => self.lexer.token_span(),
+        };
+        Some((item, span))
+    }
+
+    // 上下文关键字 import 和 global 后面是名字时才是关键字,
+    // 否则它们开始一个表达式, 例如 `global + 1`
+    fn keyword_applies(&mut self) -> bool {
+        let checkpoint = self.lexer.checkpoint();
+        let next = self.lexer.update_token();
+        self.lexer.restore(checkpoint);
+        next.is_name()
+    }
+
+    // import ::= 'import' identifier
+    // 返回要导入的模块名, 由 loader::load_program 解析成文件
+    pub fn parse_import(&mut self) -> Result<String, ParseError> {
+        self.update_token(); // eat import
+        if !self.curtok.is_name() {
+            return Err(self.unexpected(&[Token::Identifier]));
+        }
+        if self.lexer.error().is_some() {
+            return Err(self.lexer_error());
+        }
+        let name = self.lexer.identifier_text().to_string();
+        self.update_token(); // eat module name
+        Ok(name)
+    }
+
+    // top ::= definition | external | global | expression | ';'
+    // 返回下一个顶层项, 输入结束时返回 None
+    pub fn parse_top_level(&mut self) -> Option<Result<B::Item, ParseError>> {
+        while self.curtok == Token::Semicolon {
+            self.update_token(); // ignore top-level semicolons
+        }
+        let tok = self.curtok;
+        match tok {
+            Token::Eof if self.lexer.error().is_some() => Some(Err(self.lexer_error())),
+            Token::Eof => None,
+            // 单独解析一个文件时无法导入其它文件
+            Token::Keyword(Keyword::Import) if self.keyword_applies() => {
+                Some(self.parse_import().and_then(|_| {
+                    syntax_error("`import` is only supported when loading files with load_program")
+                }))
+            }
+            Token::Def => Some(self.parse_definition().map(B::def_item)),
+            Token::Extern => Some(self.parse_extern().map(B::extern_item)),
+            Token::Keyword(Keyword::Global) if self.keyword_applies() => {
+                Some(self.parse_global().map(B::global_item))
+            }
+            _ => Some(self.parse_top_level_expr().map(B::expr_item)),
+        }
+    }
+}
+
+// AST 中的运算符 op 的优先级, 不是二元运算符时返回 None
+pub fn binop_precedence(op: char) -> Option<i32> {
+    Operator::from_char(op).map(Operator::precedence)
+}
+
+// 运算符在源码中的写法, 例如 '&' 写作 "&&"
+pub fn binop_spelling(op: char) -> String {
+    match Operator::from_char(op) {
+        Some(op) => op.spelling().to_string(),
+        None => op.to_string(),
+    }
+}
+
+// 可以开始一个表达式的 token
+const EXPRESSION_START: [Token; 5] =
+    [Token::Identifier, Token::Number, Token::LParen, Token::LBracket, Token::Def];
+
+// 表达式之后合法的 token: expected 加上所有二元运算符和下标的 '['
+fn after_expression(expected: &[Token]) -> Vec<Token> {
+    let binops = Operator::ALL.map(Token::Op);
+    expected.iter().copied().chain(binops).chain([Token::LBracket]).collect()
+}
+
+// 顶层项之后合法的 token; extern 之外的项以表达式结尾, 之后还可以接二元运算符
+fn after_item(extern_item: bool) -> Vec<Token> {
+    let expected = [Token::Semicolon, Token::Eof];
+    if extern_item {
+        expected.to_vec()
+    } else {
+        after_expression(&expected)
+    }
+}
+
+pub fn is_error(ast: &Arc<dyn ExprAST>) -> bool {
+    matches!(ast.kind(), ExprASTKind::Error)
+}
+
+// one top-level item of a program, tagged by kind so drivers don't need to downcast
+#[derive(Debug, Clone)]
+pub enum TopLevelItem {
+    Def(Arc<FunctionAST>),
+    Extern(Arc<PrototypeAST>),
+    Global(Arc<GlobalAST>),
+    // top-level expression, wrapped in its anonymous function __anon_exprN
+    Expr(Arc<FunctionAST>),
+}
+impl PartialEq for TopLevelItem {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (TopLevelItem::Def(left), TopLevelItem::Def(right)) => left == right,
+            (TopLevelItem::Extern(left), TopLevelItem::Extern(right)) => left == right,
+            (TopLevelItem::Global(left), TopLevelItem::Global(right)) => left == right,
+            (TopLevelItem::Expr(left), TopLevelItem::Expr(right)) => left == right,
+            _ => false,
+        }
+    }
+}
+impl Eq for TopLevelItem {}
+impl Hash for TopLevelItem {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            TopLevelItem::Def(function) => function.hash(state),
+            TopLevelItem::Extern(proto) => proto.hash(state),
+            TopLevelItem::Global(global) => global.hash(state),
+            TopLevelItem::Expr(expr) => expr.hash(state),
+        }
+    }
+}
+impl TopLevelItem {
+    pub fn as_ast(&self) -> Arc<dyn ExprAST> {
+        match self {
+            TopLevelItem::Def(function) => function.clone(),
+            TopLevelItem::Extern(proto) => proto.clone(),
+            TopLevelItem::Global(global) => global.clone(),
+            TopLevelItem::Expr(expr) => expr.clone(),
         }
     }
 }
 
+// a whole source file: definitions, externs, globals and top-level expressions in order
+#[derive(Debug)]
+pub struct Program {
+    items: Vec<TopLevelItem>,
+    interner: Interner, // 解析时驻留的名字, 不参与比较
+}
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        self.items == other.items
+    }
+}
+impl Hash for Program {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.items.hash(state);
+    }
+}
+impl Program {
+    pub fn new(items: Vec<TopLevelItem>) -> Self {
+        Program::with_interner(items, Interner::new())
+    }
+    pub fn with_interner(items: Vec<TopLevelItem>, interner: Interner) -> Self {
+        Program { items, interner }
+    }
+    pub fn items(&self) -> &[TopLevelItem] {
+        &self.items
+    }
+    pub fn interner(&self) -> &Interner {
+        &self.interner
+    }
+    // 结构不同时 panic, 并指出第一个不同的顶层项
+    #[track_caller]
+    pub fn assert_structurally_eq(&self, other: &Program) {
+        for (i, (left, right)) in self.items.iter().zip(&other.items).enumerate() {
+            assert!(
+                left == right,
+                "programs differ at item {}:\n  left: {:?}\n right: {:?}",
+                i,
+                left,
+                right
+            );
+        }
+        assert!(
+            self.items.len() == other.items.len(),
+            "programs have {} and {} items",
+            self.items.len(),
+            other.items.len()
+        );
+    }
+}
+
+pub fn parse_str(source: &str) -> Result<Program, Vec<ParseError>> {
+    ASTParser::new(Lexer::from_str(source)).parse_program()
+}
+
+pub fn parse_file(path: impl AsRef<Path>) -> Result<Program, Vec<ParseError>> {
+    let open = || -> Result<_, LexError> { Ok(Lexer::new(BufReader::new(File::open(path)?))?) };
+    let lexer = open().map_err(|e| vec![ParseError::LexerError(e)])?;
+    ASTParser::new(lexer).parse_program()
+}
+
+// 在 rayon 线程池上并行解析多个文件, 结果按 paths 的顺序排列
+#[cfg(feature = "rayon")]
+pub fn parse_files(paths: &[std::path::PathBuf]) -> Vec<Result<Program, Vec<ParseError>>> {
+    use rayon::prelude::*;
+    paths.par_iter().map(parse_file).collect()
+}
+
 #[cfg(test)]
 mod test_ast {
     use super::*;
@@ -534,7 +3191,572 @@ mod test_ast {
         let mut astparser1 = ASTParser::new(lexer1);
         astparser1.lexer.update_token();
         let ast1 = astparser1.parse_number_expr();
-        let _ast2 = Rc::new(NumberExprAST::new(123.0));
-        assert!(matches!(ast1,_ast2))
+        let ast2: Arc<dyn ExprAST> = Arc::new(NumberExprAST::new(123.0));
+        assert_eq!(*ast1, *ast2);
+        let ast3: Arc<dyn ExprAST> = Arc::new(NumberExprAST::new(124.0));
+        assert_ne!(*ast1, *ast3);
+    }
+
+    #[cfg(test)]
+    fn create_parser(input: &str) -> ASTParser<MockReader> {
+        let mut astparser = ASTParser::new(create_lexer(input));
+        astparser.update_token();
+        astparser
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        let mut astparser1 = create_parser("a + b * (c - 1) < d");
+        let ast1 = astparser1.parse_expression();
+        assert_eq!(
+            format!("{:?}", ast1),
+            "BinaryExprAST { op: '<', lhs: BinaryExprAST { op: '+', lhs: VariableExprAST { name: \"a\", span: 0..1 }, \
+             rhs: BinaryExprAST { op: '*', lhs: VariableExprAST { name: \"b\", span: 4..5 }, \
+             rhs: BinaryExprAST { op: '-', lhs: VariableExprAST { name: \"c\", span: 9..10 }, \
+             rhs: NumberExprAST { val: 1.0, span: 13..14 }, span: 9..14 }, span: 4..15 }, span: 0..15 }, \
+             rhs: VariableExprAST { name: \"d\", span: 18..19 }, span: 0..19 }"
+        );
+        assert_eq!(astparser1.curtok, Token::Eof);
+
+        // || 最松, 其次是 &&, 都比比较运算松
+        let mut astparser2 = create_parser("a || b && c < d");
+        assert_eq!(
+            format!("{:?}", astparser2.parse_expression()),
+            "BinaryExprAST { op: '|', lhs: VariableExprAST { name: \"a\", span: 0..1 }, \
+             rhs: BinaryExprAST { op: '&', lhs: VariableExprAST { name: \"b\", span: 5..6 }, \
+             rhs: BinaryExprAST { op: '<', lhs: VariableExprAST { name: \"c\", span: 10..11 }, \
+             rhs: VariableExprAST { name: \"d\", span: 14..15 }, span: 10..15 }, span: 5..15 }, span: 0..15 }"
+        );
+        // 单独的 & 不是运算符
+        let mut astparser3 = create_parser("a & b");
+        astparser3.parse_expression();
+        assert_eq!(astparser3.curtok, Token::Unknown('&'));
+    }
+
+    #[test]
+    fn test_parse_call() {
+        let mut astparser1 = create_parser("foo(x, 2)");
+        let ast1 = astparser1.parse_expression();
+        assert_eq!(
+            format!("{:?}", ast1),
+            "CallExprAST { callee: \"foo\", args: [VariableExprAST { name: \"x\", span: 4..5 }, \
+             NumberExprAST { val: 2.0, span: 7..8 }], span: 0..9, callee_span: 0..3 }"
+        );
+        let mut astparser2 = create_parser("foo()");
+        assert!(matches!(astparser2.parse_expression().kind(), ExprASTKind::Call));
+    }
+
+    #[test]
+    fn test_parse_definition_and_extern() {
+        let mut astparser1 = create_parser("def add(x y) x + y");
+        let function = astparser1.parse_definition().unwrap();
+        assert_eq!(function.proto.name, "add");
+        assert_eq!(function.proto.args, vec!["x", "y"]);
+        assert!(matches!(function.body.kind(), ExprASTKind::Binary));
+
+        let mut astparser2 = create_parser("extern sin(a)");
+        let proto = astparser2.parse_extern().unwrap();
+        assert_eq!(proto.name, "sin");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut astparser1 = create_parser("foo(x; y)");
+        let ast1 = astparser1.parse_expression();
+        let error = ast1.as_any().downcast_ref::<ErrorAST>().unwrap().get_error();
+        assert!(matches!(error, ParseError::UnexpectedToken(Token::Semicolon, ..)));
+        assert!(!error.is_incomplete());
+
+        let mut astparser2 = create_parser(")");
+        assert!(astparser2.parse_top_level_expr().is_err());
+    }
+
+    #[test]
+    fn test_expected_tokens() {
+        let binops = [
+            Token::Op(Operator::Or),
+            Token::Op(Operator::And),
+            Token::Op(Operator::Less),
+            Token::Op(Operator::Plus),
+            Token::Op(Operator::Minus),
+            Token::Op(Operator::Times),
+            Token::LBracket,
+        ];
+        let error = |input: &str| parse_str(input).unwrap_err().remove(0);
+
+        let mut expected = vec![Token::RParen, Token::Comma];
+        expected.extend(binops);
+        assert_eq!(
+            error("foo(x y)"),
+            ParseError::UnexpectedToken(Token::Identifier, expected, Span::new(6, 7))
+        );
+        assert_eq!(
+            error("foo(x y)").to_string(),
+            "expected one of ')', ',', '||', '&&', '<', '+', '-', '*', '[', got Identifier at 6..7"
+        );
+        assert_eq!(
+            error("1 + ;"),
+            ParseError::UnexpectedToken(Token::Semicolon, EXPRESSION_START.to_vec(), Span::new(4, 5))
+        );
+        assert_eq!(
+            error("def f(x 1) x").to_string(),
+            "expected one of Identifier, ':', ',', ')', got Number at 8..9"
+        );
+        assert_eq!(
+            error("def f(x: bool 1) x").to_string(),
+            "expected one of Identifier, ',', ')', got Number at 14..15"
+        );
+        assert_eq!(error("extern 1").to_string(), "expected Identifier, got Number at 7..8");
+        assert_eq!(
+            error("extern f(x) g").to_string(),
+            "expected one of ';', Eof, got Identifier at 12..13"
+        );
+        assert_eq!(error("def f").to_string(), "unexpected end of input, expected '('");
+    }
+
+    #[test]
+    fn test_parse_top_level() {
+        let mut astparser1 = create_parser("def f(x) x; extern g();; f(1)\n g()");
+        let mut items = Vec::new();
+        while let Some(item) = astparser1.parse_top_level() {
+            items.push(item.unwrap());
+        }
+        assert!(matches!(
+            items.as_slice(),
+            [
+                TopLevelItem::Def(_),
+                TopLevelItem::Extern(_),
+                TopLevelItem::Expr(_),
+                TopLevelItem::Expr(_)
+            ]
+        ));
+        // 顶层表达式被包装成匿名函数
+        let TopLevelItem::Expr(anon) = &items[3] else {
+            unreachable!()
+        };
+        assert_eq!(anon.proto().name(), "__anon_expr1");
+        assert!(matches!(anon.body().kind(), ExprASTKind::Call));
+    }
+
+    #[test]
+    fn test_parse_token_too_long() {
+        let long = "x".repeat(DEFAULT_MAX_TOKEN_LEN + 1);
+        for input in [format!("{} + 1", long), format!("def {}(a) a", long), format!("extern f({})", long)] {
+            match parse_str(&input) {
+                Err(errors) => assert!(
+                    matches!(&errors[0], ParseError::LexerError(LexError::TokenTooLong(..))),
+                    "{:?}",
+                    errors
+                ),
+                Ok(_) => panic!("parsed a token longer than the limit"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_top_level_with_span() {
+        let mut astparser1 = create_parser("def f(x) x + 1;; extern g()\n f(2) )");
+        let mut spans = Vec::new();
+        while let Some((item, span)) = astparser1.parse_top_level_with_span() {
+            spans.push((item.is_ok(), span));
+            if item.is_err() {
+                astparser1.update_token();
+            }
+        }
+        assert_eq!(
+            spans,
+            [
+                (true, Span::new(0, 14)),
+                (true, Span::new(17, 27)),
+                (true, Span::new(29, 33)),
+                (false, Span::new(34, 35))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_str() {
+        let program = parse_str("def f(x) x + 1;\nextern g(a b);\nf(g(1, 2))").unwrap();
+        assert_eq!(program.items().len(), 3);
+        assert!(matches!(program.items()[2], TopLevelItem::Expr(_)));
+        assert!(parse_str("").unwrap().items().is_empty());
+        assert!(parse_str(";;").unwrap().items().is_empty());
+
+        let errors = parse_str("def 1;\nf(1);\n1 + )").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], ParseError::UnexpectedToken(Token::Number, ..)));
+        assert!(matches!(errors[1], ParseError::UnexpectedToken(Token::RParen, ..)));
+    }
+
+    #[test]
+    fn test_parse_program_separators() {
+        let program = parse_str("extern sin(x); sin(1); 2 + 3;").unwrap();
+        let names: Vec<&str> = program
+            .items()
+            .iter()
+            .map(|item| match item {
+                TopLevelItem::Def(function) => function.proto().name(),
+                TopLevelItem::Extern(proto) => proto.name(),
+                TopLevelItem::Global(global) => global.name(),
+                TopLevelItem::Expr(function) => function.proto().name(),
+            })
+            .collect();
+        assert_eq!(names, ["sin", "__anon_expr0", "__anon_expr1"]);
+
+        let anon = program.items()[2].as_ast();
+        let anon = anon.as_any().downcast_ref::<FunctionAST>().unwrap();
+        assert!(anon.proto().args().is_empty());
+        assert!(matches!(anon.body().kind(), ExprASTKind::Binary));
+
+        // 缺少分隔符: 报错, 但两项都会被解析
+        let errors = parse_str("def f(x) x f(1)").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], ParseError::UnexpectedToken(Token::Identifier, ..)));
+    }
+
+    #[test]
+    fn test_try_parse() {
+        // f(1) 不是原型, 回退后按表达式解析
+        let mut astparser1 = create_parser("f(1) + x");
+        let error = astparser1.try_parse(|parser| parser.parse_prototype()).unwrap_err();
+        assert!(matches!(error, ParseError::UnexpectedToken(Token::Number, ..)));
+        assert_eq!(astparser1.curtok, Token::Identifier);
+        let expr = astparser1.parse_expression();
+        assert_eq!(crate::format::print_expr(expr.as_ref()), "f(1) + x");
+        assert_eq!(astparser1.curtok, Token::Eof);
+
+        let mut astparser2 = create_parser("f(x) x");
+        let proto = astparser2.try_parse(|parser| parser.parse_prototype()).unwrap();
+        assert_eq!(proto.args(), [Symbol::from("x")]);
+        assert_eq!(astparser2.curtok, Token::Identifier);
+        assert!(astparser2.lexer.history.is_empty());
+    }
+
+    #[test]
+    fn test_parse_varargs_extern() {
+        let program = parse_str("extern printf(fmt, ...); extern any(...) -> bool; extern f(x)").unwrap();
+        let protos: Vec<&PrototypeAST> = program
+            .items()
+            .iter()
+            .map(|item| match item {
+                TopLevelItem::Extern(proto) => proto.as_ref(),
+                _ => panic!("expected an extern"),
+            })
+            .collect();
+        assert_eq!(protos[0].args(), [Symbol::from("fmt")]);
+        assert!(protos[0].is_varargs());
+        assert!(protos[1].args().is_empty() && protos[1].is_varargs());
+        assert_eq!(protos[1].return_type(), Type::Bool);
+        assert!(!protos[2].is_varargs());
+        assert_ne!(*protos[2], PrototypeAST::new("f", vec!["x".into()]).with_varargs(true));
+
+        // ... 只能在 extern 的最后, 定义和局部函数不能有
+        let unexpected = |input: &str| match parse_str(input).unwrap_err().remove(0) {
+            ParseError::UnexpectedToken(tok, expected, _) => (tok, expected),
+            error => panic!("{:?}", error),
+        };
+        assert_eq!(unexpected("extern g(..., x)"), (Token::Comma, vec![Token::RParen]));
+        let not_allowed = (Token::Ellipsis, vec![Token::Identifier, Token::RParen]);
+        assert_eq!(unexpected("def h(x, ...) x"), not_allowed);
+        assert_eq!(unexpected("def k(...) 1 in 2"), not_allowed);
+        assert_eq!(
+            parse_str("extern f(x").unwrap_err(),
+            [ParseError::UnexpectedEof(vec![
+                Token::Identifier,
+                Token::Colon,
+                Token::Comma,
+                Token::Ellipsis,
+                Token::RParen
+            ], Span::new(10, 10))]
+        );
+    }
+
+    #[test]
+    fn test_parse_global() {
+        let program = parse_str("global rate = 0.5; def f(x) rate * x").unwrap();
+        let TopLevelItem::Global(global) = &program.items()[0] else {
+            panic!("expected a global");
+        };
+        assert_eq!(global.name(), "rate");
+        let init = global.init().as_any().downcast_ref::<NumberExprAST>();
+        assert_eq!(init.map(NumberExprAST::val), Some(0.5));
+        assert!(matches!(program.items()[1], TopLevelItem::Def(_)));
+
+        let errors = parse_str("global 1; global x 2; global y = ; global z = 1 2").unwrap_err();
+        let expected: Vec<Vec<Token>> = errors
+            .iter()
+            .map(|error| match error {
+                ParseError::UnexpectedToken(_, expected, _) => expected.clone(),
+                _ => panic!("{:?}", error),
+            })
+            .collect();
+        // global 后面不是名字时是表达式, 缺少分隔符
+        assert_eq!(expected[0], after_expression(&[Token::Semicolon, Token::Eof]));
+        assert_eq!(expected[1], [Token::Equals]);
+        assert_eq!(expected[2], EXPRESSION_START);
+        assert_eq!(expected[3], after_expression(&[Token::Semicolon, Token::Eof]));
+    }
+
+    #[test]
+    fn test_contextual_keywords() {
+        // 不在关键字的位置上时是普通的名字
+        let program = parse_str("def f(in) in; def global(import) import + 1; global(2) + global").unwrap();
+        let names: Vec<&str> = program.items()[..2]
+            .iter()
+            .map(|item| match item {
+                TopLevelItem::Def(function) => function.proto().args()[0].as_str(),
+                _ => panic!("expected a definition"),
+            })
+            .collect();
+        assert_eq!(names, ["in", "import"]);
+        assert!(matches!(program.items()[2], TopLevelItem::Expr(_)));
+
+        let program = parse_str("global in = 1; def g(x) def in(y) y in in(x)").unwrap();
+        let TopLevelItem::Global(global) = &program.items()[0] else {
+            panic!("expected a global");
+        };
+        assert_eq!(global.name(), "in");
+        assert_eq!(program.items().len(), 2);
+        assert!(matches!(parse_str("import + 1").unwrap().items()[0], TopLevelItem::Expr(_)));
+    }
+
+    #[test]
+    fn test_parse_closure() {
+        let program = parse_str("def f(x) def add(y) x + y in add(1) * 2").unwrap();
+        let TopLevelItem::Def(f) = &program.items()[0] else {
+            panic!("expected a definition");
+        };
+        let closure = f.body().as_any().downcast_ref::<ClosureExprAST>().unwrap();
+        assert_eq!(closure.function().proto().name(), "add");
+        assert_eq!(closure.function().proto().args(), ["y"]);
+        assert!(matches!(closure.function().body().kind(), ExprASTKind::Binary));
+        // in 之后的表达式延伸到最后
+        assert!(matches!(closure.body().kind(), ExprASTKind::Binary));
+
+        let errors = parse_str("def f(x) def g(y) y g(1)").unwrap_err();
+        let mut expected = vec![Token::Keyword(Keyword::In)];
+        expected.extend(after_expression(&[]));
+        assert_eq!(
+            errors[0],
+            ParseError::UnexpectedToken(Token::Identifier, expected, Span::new(20, 21))
+        );
+        assert!(parse_str("def f(x) def g(y) y in").unwrap_err()[0].is_incomplete());
+    }
+
+    #[test]
+    fn test_program_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Program>();
+        assert_send_sync::<TopLevelItem>();
+        assert_send_sync::<Arc<dyn ExprAST>>();
+
+        // 解析得到的程序可以交给其它线程处理
+        let program = Arc::new(parse_str("def f(x) x * 2; f(3)").unwrap());
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let program = Arc::clone(&program);
+                std::thread::spawn(move || format::print_item(&program.items()[i]))
+            })
+            .collect();
+        let printed: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(printed, ["def f(x)\n    x * 2", "f(3)"]);
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let path = std::env::temp_dir().join("kaleidoscope_test_parse_file.k");
+        std::fs::write(&path, "extern sin(x);\nsin(1);\n").unwrap();
+        let program = parse_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(program.items().len(), 2);
+
+        let errors = parse_file(&path).unwrap_err();
+        assert!(matches!(
+            &errors[..],
+            [ParseError::LexerError(LexError::Io(io::ErrorKind::NotFound, ..))]
+        ));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parse_files() {
+        let dir = std::env::temp_dir();
+        let paths: Vec<std::path::PathBuf> = (0..8)
+            .map(|i| dir.join(format!("kaleidoscope_test_parse_files_{}.k", i)))
+            .collect();
+        for (i, path) in paths.iter().enumerate() {
+            let source = if i == 5 { "def 1".to_string() } else { format!("def f{}(x) x; f{}(1)", i, i) };
+            std::fs::write(path, source).unwrap();
+        }
+        let missing = dir.join("kaleidoscope_test_parse_files_missing.k");
+        let mut all = paths.clone();
+        all.push(missing);
+        let results = parse_files(&all);
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        assert_eq!(results.len(), 9);
+        for (i, result) in results.iter().enumerate().take(8) {
+            match result {
+                Ok(program) => {
+                    assert_ne!(i, 5);
+                    assert_eq!(format::print_item(&program.items()[1]), format!("f{}(1)", i));
+                }
+                Err(errors) => {
+                    assert_eq!(i, 5);
+                    assert!(matches!(errors[0], ParseError::UnexpectedToken(Token::Number, ..)));
+                }
+            }
+        }
+        assert!(matches!(results[8].as_ref().unwrap_err()[0], ParseError::LexerError(LexError::Io(..))));
+    }
+
+    #[test]
+    fn test_accessors() {
+        let program = parse_str("def f(x y) x * g(y, 2)").unwrap();
+        let TopLevelItem::Def(function) = &program.items()[0] else {
+            unreachable!()
+        };
+        assert_eq!(function.proto().name(), "f");
+        assert_eq!(function.proto().args(), ["x", "y"]);
+
+        let binary = function.body().as_any().downcast_ref::<BinaryExprAST>().unwrap();
+        assert_eq!(binary.op(), '*');
+        let lhs = binary.lhs().as_any().downcast_ref::<VariableExprAST>().unwrap();
+        assert_eq!(lhs.name(), "x");
+
+        let call = binary.rhs().as_any().downcast_ref::<CallExprAST>().unwrap();
+        assert_eq!(call.callee(), "g");
+        assert_eq!(call.args().len(), 2);
+        let number = call.args()[1].as_any().downcast_ref::<NumberExprAST>().unwrap();
+        assert_eq!(number.val(), 2.0);
+    }
+
+    #[test]
+    fn test_type_annotations() {
+        let program = parse_str("def f(x: double, flag: bool) -> bool flag; extern g(a b: bool); def h(x) x").unwrap();
+        let protos: Vec<&PrototypeAST> = program
+            .items()
+            .iter()
+            .map(|item| match item {
+                TopLevelItem::Def(function) => function.proto().as_ref(),
+                TopLevelItem::Extern(proto) => proto.as_ref(),
+                TopLevelItem::Global(_) | TopLevelItem::Expr(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(protos[0].args(), ["x", "flag"]);
+        assert_eq!(protos[0].arg_types(), [Type::Double, Type::Bool]);
+        assert_eq!(protos[0].return_type(), Type::Bool);
+        assert_eq!(protos[1].arg_types(), [Type::Double, Type::Bool]);
+        assert_eq!(protos[1].return_type(), Type::Double);
+        // 没有标注时都是 double
+        assert!(!protos[2].is_typed());
+        assert_eq!(*protos[2], PrototypeAST::new("h", vec!["x".into()]));
+
+        let errors = parse_str("def f(x: int) x").unwrap_err();
+        assert_eq!(errors[0], ParseError::SyntaxError("unknown type `int`".to_string()));
+        assert!(matches!(
+            parse_str("def f(x) - x").unwrap_err()[0],
+            ParseError::UnexpectedToken(Token::Op(Operator::Minus), ..)
+        ));
+    }
+
+    #[test]
+    fn test_structural_eq() {
+        let program1 = parse_str("def f(x) x + 1; f(2)").unwrap();
+        let program2 = parse_str("def f(x)\n  x+1;\nf( 2 );").unwrap();
+        program1.assert_structurally_eq(&program2);
+        assert!(program1 == program2);
+
+        let x: Arc<dyn ExprAST> = Arc::new(VariableExprAST::new("x".to_string()));
+        let built = Program::new(vec![
+            TopLevelItem::Def(Arc::new(FunctionAST::new(
+                Arc::new(PrototypeAST::new("f", vec!["x".into()])),
+                Arc::new(BinaryExprAST::new('+', x, Arc::new(NumberExprAST::new(1.0)))),
+            ))),
+            TopLevelItem::Expr(Arc::new(FunctionAST::new(
+                Arc::new(PrototypeAST::new("__anon_expr0".to_string(), vec![])),
+                Arc::new(CallExprAST::new("f".to_string(), vec![Arc::new(NumberExprAST::new(2.0))])),
+            ))),
+        ]);
+        built.assert_structurally_eq(&program1);
+
+        let program3 = parse_str("def f(x) x - 1; f(2)").unwrap();
+        assert!(program1 != program3);
+        // 不同种类的节点永远不相等
+        let var: Arc<dyn ExprAST> = Arc::new(VariableExprAST::new("f".to_string()));
+        let proto: Arc<dyn ExprAST> = Arc::new(PrototypeAST::new("f".to_string(), vec![]));
+        assert_ne!(*var, *proto);
+    }
+
+    #[test]
+    fn test_structural_hash() {
+        use std::collections::HashSet;
+        let mut set: HashSet<TopLevelItem> = HashSet::new();
+        for source in ["a + 1", "a+1", "(a) + (1)", "a * 1"] {
+            set.insert(parse_str(source).unwrap().items()[0].clone());
+        }
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "programs differ at item 1")]
+    fn test_assert_structurally_eq_panics() {
+        let program1 = parse_str("extern g(); g()").unwrap();
+        let program2 = parse_str("extern g(); g(1)").unwrap();
+        program1.assert_structurally_eq(&program2);
+    }
+
+    #[test]
+    fn test_parse_malformed_number() {
+        let errors = parse_str("def f(x) x + 1e; f(2)").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![ParseError::LexerError(LexError::MalformedNumber("1e".to_string(), Span::new(13, 15)))]
+        );
+    }
+
+    #[test]
+    fn test_parse_lexer_errors() {
+        // 无效的 UTF-8 报告为词法错误, 而不是意外的 U+FFFD
+        let source = MockReader { data: b"1 + \xff".to_vec(), position: 0 };
+        let mut parser = ASTParser::new(Lexer::new(source).unwrap());
+        assert_eq!(
+            parser.parse_program().unwrap_err(),
+            vec![ParseError::LexerError(LexError::InvalidUtf8(Span::new(4, 5)))]
+        );
+
+        // 读取失败时在中断处报错, 然后结束
+        struct Failing(&'static [u8]);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::Error::other("disk on fire"));
+                }
+                let len = buf.len().min(self.0.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+        for source in [&b"def f(x) x +"[..], b"def f(x) x;"] {
+            let mut parser = ASTParser::new(Lexer::new(Failing(source)).unwrap());
+            let errors = parser.parse_program().unwrap_err();
+            assert!(
+                matches!(&errors[..], [ParseError::LexerError(LexError::Io(io::ErrorKind::Other, ..))]),
+                "{:?}",
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incomplete() {
+        for input in ["def f(x)", "def f(x", "foo(1,", "(1 + 2", "1 +", "extern"] {
+            let mut astparser1 = create_parser(input);
+            let error = astparser1.parse_top_level().unwrap().unwrap_err();
+            assert!(error.is_incomplete(), "{}: {}", input, error);
+        }
     }
 }
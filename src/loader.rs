@@ -0,0 +1,368 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::intern::Interner;
+use crate::{ASTParser, LexError, Lexer, ParseError, Program, Span};
+
+// 模块 `import name` 对应的文件扩展名
+pub const MODULE_EXTENSION: &str = "k";
+
+// a parse error together with the file it occurred in; its span is an
+// offset into that file
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadError {
+    pub file: PathBuf,
+    pub error: ParseError,
+}
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.file.display(), self.error)
+    }
+}
+impl std::error::Error for LoadError {}
+
+// A program assembled from a file and everything it imports. Imported
+// items take the place of the `import`, so the order is the same as if
+// the files had been pasted together.
+//
+// Spans in the program are offsets into all files laid out one after the
+// other in the order they were loaded, with a gap of one byte between
+// them; `locate` turns one back into a file and an offset in that file.
+#[derive(Debug)]
+pub struct LoadedProgram {
+    program: Program,
+    files: Vec<PathBuf>,    // 按第一次加载的顺序, files[0] 是入口文件
+    starts: Vec<usize>,     // 每个文件在整个程序中的起始偏移, 递增
+    item_files: Vec<usize>, // 每个顶层项来自哪个文件, 是 files 中的下标
+}
+impl LoadedProgram {
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+    // 第 item 个顶层项所在的文件, 该项的所有 AST 节点都来自这个文件
+    pub fn file_of(&self, item: usize) -> &Path {
+        &self.files[self.item_files[item]]
+    }
+    // 程序中的位置所在的文件和文件内的位置, 例如 sema 诊断的位置
+    pub fn locate(&self, span: Span) -> (&Path, Span) {
+        let file = self.starts.partition_point(|start| *start <= span.start) - 1;
+        let start = self.starts[file];
+        (
+            &self.files[file],
+            Span::new(span.start - start, span.end - start),
+        )
+    }
+}
+
+// Parses `path` and, recursively, the files it imports. `import lib`
+// loads `lib.k` from the importing file's directory; a file imported a
+// second time is skipped, and an import that leads back to a file still
+// being parsed is reported as a cycle. Parsing continues after errors and
+// all of them are returned.
+pub fn load_program(path: impl AsRef<Path>) -> Result<LoadedProgram, Vec<LoadError>> {
+    let mut loader = Loader {
+        files: Vec::new(),
+        canonical: Vec::new(),
+        starts: Vec::new(),
+        end: 0,
+        stack: Vec::new(),
+        errors: Vec::new(),
+    };
+    let path = path.as_ref();
+    if let Err(error) = loader.open(path) {
+        return Err(vec![LoadError {
+            file: path.to_path_buf(),
+            error: open_error(path, error, Span::new(0, 0)),
+        }]);
+    }
+
+    let mut items = Vec::new();
+    let mut item_files = Vec::new();
+    let mut anon_count = 0; // 所有文件共用匿名函数的编号
+    let mut interner = Interner::new(); // 合并各文件解析出的名字
+    // 栈顶是正在解析的文件
+    while let Some((file, parser)) = loader.stack.last_mut() {
+        let file = *file;
+        if let Some((import, span)) = parser.parse_next_import() {
+            match import {
+                Ok(name) => loader.import(file, &name, span),
+                Err(error) => loader.error(file, error),
+            }
+            continue;
+        }
+        parser.anon_count = anon_count;
+        let item = parser.parse_next_item();
+        anon_count = parser.anon_count;
+        match item {
+            None => {
+                interner.extend(&parser.interner);
+                loader.stack.pop();
+            }
+            Some((Ok(item), _)) => {
+                items.push(item);
+                item_files.push(file);
+            }
+            Some((Err(error), _)) => loader.error(file, error),
+        }
+    }
+
+    if loader.errors.is_empty() {
+        Ok(LoadedProgram {
+            program: Program::with_interner(items, interner),
+            files: loader.files,
+            starts: loader.starts,
+            item_files,
+        })
+    } else {
+        Err(loader.errors)
+    }
+}
+
+// 打开文件失败和 parse_file 一样是 K0003, 位置是入口文件的开头或 import
+fn open_error(path: &Path, error: io::Error, span: Span) -> ParseError {
+    let message = format!("{}: {}", path.display(), error);
+    ParseError::LexerError(LexError::Io(error.kind(), message, span))
+}
+
+struct Loader {
+    files: Vec<PathBuf>,
+    canonical: Vec<PathBuf>, // files 的规范路径, 用于判断是否是同一个文件
+    starts: Vec<usize>,      // 每个文件的起始偏移, 见 LoadedProgram
+    end: usize,              // 下一个文件的起始偏移
+    stack: Vec<(usize, ASTParser<BufReader<File>>)>, // 正在解析的文件, 每个一个解析器
+    errors: Vec<LoadError>,
+}
+impl Loader {
+    // 开始解析 path, 压入解析器栈. 文件中的位置从 self.end 算起
+    fn open(&mut self, path: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        let canonical = path.canonicalize()?;
+        let len = file.metadata()?.len() as usize;
+        let mut lexer = Lexer::new(BufReader::new(file))?;
+        let start = self.end;
+        (lexer.pos, lexer.char_pos, lexer.tok_start) = (start, start, start);
+        let mut parser = ASTParser::new(lexer);
+        parser.prev_end = start;
+        parser.update_token();
+        self.files.push(path.to_path_buf());
+        self.canonical.push(canonical);
+        self.starts.push(start);
+        // 空一个字节, 文件末尾的位置不会和下一个文件的开头重合
+        self.end = start + len + 1;
+        self.stack.push((self.files.len() - 1, parser));
+        Ok(())
+    }
+
+    fn import(&mut self, from: usize, name: &str, span: Span) {
+        let dir = self.files[from].parent().unwrap_or(Path::new(""));
+        let path = dir.join(name).with_extension(MODULE_EXTENSION);
+        let loaded = path
+            .canonicalize()
+            .ok()
+            .and_then(|canonical| self.canonical.iter().position(|file| *file == canonical));
+        match loaded {
+            Some(file) if self.stack.iter().any(|(open, _)| *open == file) => {
+                // 从 file 开始的导入链又回到了 file
+                let start = self
+                    .stack
+                    .iter()
+                    .position(|(open, _)| *open == file)
+                    .unwrap();
+                let mut chain: Vec<String> = self.stack[start..]
+                    .iter()
+                    .map(|(open, _)| self.files[*open].display().to_string())
+                    .collect();
+                chain.push(self.files[file].display().to_string());
+                let error =
+                    ParseError::GeneralError(format!("import cycle: {}", chain.join(" -> ")));
+                self.error(from, error);
+            }
+            Some(_) => {} // 已经导入过
+            None => {
+                if let Err(error) = self.open(&path) {
+                    self.error(from, open_error(&path, error, span));
+                }
+            }
+        }
+    }
+
+    // 错误中的位置换成文件内的偏移
+    fn error(&mut self, file: usize, error: ParseError) {
+        let start = self.starts[file];
+        let error = error.map_span(|span| Span::new(span.start - start, span.end - start));
+        self.errors.push(LoadError {
+            file: self.files[file].clone(),
+            error,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_loader {
+    use super::*;
+    use crate::TopLevelItem;
+    use crate::format::print_item;
+    use std::fs;
+
+    // 在临时目录中写入 files, 返回目录
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kaleidoscope_test_loader_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, source) in files {
+            fs::write(dir.join(file), source).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_load_program() {
+        let dir = write_files(
+            "imports",
+            &[
+                ("main.k", "import math;\nimport lib;\nsquare(2) + twice(3)"),
+                ("math.k", "extern sin(x);\ndef square(x) x * x"),
+                // 重复的导入被跳过
+                (
+                    "lib.k",
+                    "import util; import math;\ndef twice(x) helper(x) * 2",
+                ),
+                ("util.k", "def helper(x) x;\nhelper(0)"),
+            ],
+        );
+        let loaded = load_program(dir.join("main.k")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let items: Vec<String> = loaded.program().items().iter().map(print_item).collect();
+        assert_eq!(
+            items,
+            [
+                "extern sin(x)",
+                "def square(x)\n    x * x",
+                "def helper(x)\n    x",
+                "helper(0)",
+                "def twice(x)\n    helper(x) * 2",
+                "square(2) + twice(3)",
+            ]
+        );
+        let files: Vec<PathBuf> = (0..items.len())
+            .map(|item| {
+                loaded
+                    .file_of(item)
+                    .strip_prefix(&dir)
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .collect();
+        let expected = ["math.k", "math.k", "util.k", "util.k", "lib.k", "main.k"];
+        assert_eq!(files, expected.map(PathBuf::from));
+        assert_eq!(loaded.files().len(), 4);
+
+        // 匿名函数的编号在所有文件中连续
+        let anon: Vec<&str> = loaded
+            .program()
+            .items()
+            .iter()
+            .filter_map(|item| match item {
+                TopLevelItem::Expr(function) => Some(function),
+                _ => None,
+            })
+            .map(|function| function.proto().name())
+            .collect();
+        assert_eq!(anon, ["__anon_expr0", "__anon_expr1"]);
+        // 每个文件解析出的名字都合并进了 program
+        let interner = loaded.program().interner();
+        assert!(
+            ["sin", "helper", "twice", "square"]
+                .iter()
+                .all(|name| interner.get(name).is_some())
+        );
+    }
+
+    #[test]
+    fn test_locate() {
+        let dir = write_files(
+            "locate",
+            &[
+                (
+                    "main.k",
+                    "import lib;
+f(1)",
+                ),
+                ("lib.k", "def f(x) x + y"),
+            ],
+        );
+        let loaded = load_program(dir.join("main.k")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // 导入的函数中的诊断能找到它所在的文件
+        let diagnostics = crate::sema::analyze(loaded.program().items());
+        let located: Vec<(&str, Span)> = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let (file, span) = loaded.locate(diagnostic.span.unwrap());
+                (file.file_name().unwrap().to_str().unwrap(), span)
+            })
+            .collect();
+        assert_eq!(located, [("lib.k", Span::new(13, 14))]);
+
+        let TopLevelItem::Expr(call) = &loaded.program().items()[1] else {
+            panic!("expected a top-level expression")
+        };
+        let (file, span) = loaded.locate(call.body().span().unwrap());
+        assert_eq!(
+            (file.file_name().unwrap(), span),
+            ("main.k".as_ref(), Span::new(12, 16))
+        );
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = write_files(
+            "errors",
+            &[
+                ("main.k", "import a;\nimport missing;\n1 +"),
+                ("a.k", "import b;\ndef f(x x"),
+                ("b.k", "import a"),
+            ],
+        );
+        let errors = load_program(dir.join("main.k")).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let files: Vec<&str> = errors
+            .iter()
+            .map(|error| error.file.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(files, ["b.k", "a.k", "main.k", "main.k"]);
+        let message = errors[0].error.to_string();
+        assert!(message.starts_with("error:import cycle: "), "{}", message);
+        assert_eq!(message.matches(" -> ").count(), 2, "{}", message);
+        assert!(message.ends_with("a.k"), "{}", message);
+        // 位置是出错的文件中的偏移
+        assert!(matches!(errors[1].error, ParseError::UnexpectedEof(..)));
+        assert_eq!(errors[1].error.span(), Some(Span::new(19, 19)));
+        // 找不到导入的文件时指向 import
+        assert!(errors[2].to_string().contains("missing.k"));
+        assert_eq!(errors[2].error.code(), "K0003");
+        assert_eq!(errors[2].error.span(), Some(Span::new(10, 24)));
+        assert!(errors[3].error.is_incomplete());
+        assert_eq!(errors[3].error.span(), Some(Span::new(29, 29)));
+
+        // 打开入口文件失败和 parse_file 一样报告
+        let error = &load_program(dir.join("main.k")).unwrap_err()[0].error;
+        assert!(matches!(
+            error,
+            ParseError::LexerError(LexError::Io(io::ErrorKind::NotFound, ..))
+        ));
+        // 单独解析时不支持 import
+        assert!(matches!(
+            crate::parse_str("import lib; 1").unwrap_err()[0],
+            ParseError::SyntaxError(_)
+        ));
+    }
+}
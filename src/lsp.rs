@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{DocumentSymbolRequest, GotoDefinition, HoverRequest};
+use lsp_types::{
+    Diagnostic, DiagnosticSeverity, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ServerCapabilities, SymbolKind, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Uri,
+};
+
+use crate::format::print_prototype;
+use crate::sema::analyze_each;
+use crate::{ASTParser, Lexer, LosslessToken, PrototypeAST, Span, Token, TopLevelItem};
+
+// a function declared by `def` or `extern`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub proto: Arc<PrototypeAST>,
+    pub is_extern: bool,
+    pub span: Span,      // 整个顶层项
+    pub name_span: Span, // 原型中的函数名
+}
+impl Symbol {
+    pub fn name(&self) -> &str {
+        self.proto.name()
+    }
+    // e.g. "def f(x y)" or "extern sin(a)"
+    pub fn signature(&self) -> String {
+        let keyword = if self.is_extern { "extern" } else { "def" };
+        format!("{} {}", keyword, print_prototype(&self.proto))
+    }
+}
+
+// One open source file, analyzed as a whole on every change.
+// Everything is located by byte offsets into the text.
+#[derive(Debug, Clone)]
+pub struct Document {
+    text: String,
+    tokens: Vec<LosslessToken>,
+    symbols: Vec<Symbol>,
+    diagnostics: Vec<(Span, String)>,
+}
+impl Document {
+    pub fn new(text: String) -> Self {
+        let mut lexer = Lexer::from_str(&text);
+        let mut tokens = Vec::new();
+        loop {
+            let token = lexer.get_lossless_token();
+            let eof = token.tok == Token::Eof;
+            tokens.push(token);
+            if eof {
+                break;
+            }
+        }
+        let mut document = Document {
+            text,
+            tokens,
+            symbols: Vec::new(),
+            diagnostics: Vec::new(),
+        };
+        document.analyze();
+        document
+    }
+
+    // 解析出错后跳过出错的 token 继续, 和 parse_program 一致
+    fn analyze(&mut self) {
+        let mut parser = ASTParser::new(Lexer::from_str(&self.text));
+        parser.update_token();
+        let mut items = Vec::new();
+        while let Some((item, span)) = parser.parse_next_item() {
+            match item {
+                Ok(item) => items.push((item, span)),
+                Err(error) => self.diagnostics.push((span, error.to_string())),
+            }
+        }
+
+        for (item, span) in &items {
+            let (proto, is_extern) = match item {
+                TopLevelItem::Def(function) => (function.proto().clone(), false),
+                TopLevelItem::Extern(proto) => (proto.clone(), true),
+                TopLevelItem::Global(_) | TopLevelItem::Expr(_) => continue,
+            };
+            let name_span = self
+                .identifiers(*span)
+                .next()
+                .map_or(*span, |token| token.span);
+            self.symbols.push(Symbol {
+                proto,
+                is_extern,
+                span: *span,
+                name_span,
+            });
+        }
+
+        let top_level: Vec<TopLevelItem> = items.iter().map(|(item, _)| item.clone()).collect();
+        let diagnostics = analyze_each(&top_level);
+        for ((_, span), diagnostics) in items.iter().zip(diagnostics) {
+            for diagnostic in diagnostics {
+                let location = diagnostic.span.unwrap_or(*span);
+                self.diagnostics.push((location, diagnostic.to_string()));
+            }
+        }
+        self.diagnostics.sort_by_key(|(span, _)| span.start);
+    }
+
+    fn identifiers(&self, within: Span) -> impl Iterator<Item = &LosslessToken> {
+        self.tokens.iter().filter(move |token| {
+            token.tok.is_name() && within.start <= token.span.start && token.span.end <= within.end
+        })
+    }
+
+    // 标识符后面紧跟 '(' 时是函数名
+    fn is_call(&self, token: &LosslessToken) -> bool {
+        let next = self
+            .tokens
+            .iter()
+            .find(|next| next.span.start >= token.span.end);
+        matches!(next, Some(next) if next.tok == Token::LParen)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+    // parse errors and semantic diagnostics, in source order
+    pub fn diagnostics(&self) -> &[(Span, String)] {
+        &self.diagnostics
+    }
+
+    // the function named by the call or prototype at `offset`;
+    // a definition wins over an extern of the same name
+    pub fn definition(&self, offset: usize) -> Option<&Symbol> {
+        let token = self.tokens.iter().find(|token| {
+            token.tok.is_name() && token.span.start <= offset && offset <= token.span.end
+        })?;
+        if !self.is_call(token) {
+            return None;
+        }
+        let mut candidates = self
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.name() == token.text);
+        let first = candidates.clone().next()?;
+        Some(candidates.find(|symbol| !symbol.is_extern).unwrap_or(first))
+    }
+}
+
+// LSP positions count UTF-16 code units within a line
+pub fn to_position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character = before[line_start..].encode_utf16().count();
+    Position::new(line as u32, character as u32)
+}
+
+pub fn to_offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= position.character as usize || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn to_range(text: &str, span: Span) -> Range {
+    Range::new(to_position(text, span.start), to_position(text, span.end))
+}
+
+// Request and notification handling, independent of the transport.
+#[derive(Debug, Default)]
+pub struct Server {
+    documents: HashMap<Uri, Document>,
+}
+impl Server {
+    pub fn new() -> Self {
+        Server::default()
+    }
+
+    pub fn capabilities() -> ServerCapabilities {
+        ServerCapabilities {
+            text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+            hover_provider: Some(HoverProviderCapability::Simple(true)),
+            definition_provider: Some(OneOf::Left(true)),
+            document_symbol_provider: Some(OneOf::Left(true)),
+            ..ServerCapabilities::default()
+        }
+    }
+
+    pub fn document(&self, uri: &Uri) -> Option<&Document> {
+        self.documents.get(uri)
+    }
+
+    pub fn handle_request(&self, request: &Request) -> Response {
+        respond::<HoverRequest>(request, |params| self.hover(params))
+            .or_else(|| respond::<GotoDefinition>(request, |params| self.definition(params)))
+            .or_else(|| respond::<DocumentSymbolRequest>(request, |params| self.symbols(params)))
+            .unwrap_or_else(|| {
+                Response::new_err(
+                    request.id.clone(),
+                    ErrorCode::MethodNotFound as i32,
+                    format!("unsupported request {}", request.method),
+                )
+            })
+    }
+
+    // 文档变化时返回需要发布的诊断
+    pub fn handle_notification(&mut self, notification: Notification) -> Option<Notification> {
+        let (uri, text) = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params).ok()?;
+                (params.text_document.uri, Some(params.text_document.text))
+            }
+            // 使用全量同步, 最后一次修改就是完整的文本
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params).ok()?;
+                let text = params.content_changes.into_iter().last()?.text;
+                (params.text_document.uri, Some(text))
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params).ok()?;
+                (params.text_document.uri, None)
+            }
+            _ => return None,
+        };
+
+        let diagnostics = match text {
+            Some(text) => {
+                let document = Document::new(text);
+                let diagnostics = document
+                    .diagnostics()
+                    .iter()
+                    .map(|(span, message)| Diagnostic {
+                        range: to_range(document.text(), *span),
+                        severity: Some(DiagnosticSeverity::ERROR),
+                        source: Some("kaleidoscope".to_string()),
+                        message: message.clone(),
+                        ..Diagnostic::default()
+                    })
+                    .collect();
+                self.documents.insert(uri.clone(), document);
+                diagnostics
+            }
+            // 关闭文档时清空它的诊断
+            None => {
+                self.documents.remove(&uri);
+                Vec::new()
+            }
+        };
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        Some(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            params,
+        ))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let position = params.text_document_position_params;
+        let document = self.document(&position.text_document.uri)?;
+        let symbol = document.definition(to_offset(document.text(), position.position))?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```kaleidoscope\n{}\n```", symbol.signature()),
+            }),
+            range: None,
+        })
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let position = params.text_document_position_params;
+        let document = self.document(&position.text_document.uri)?;
+        let symbol = document.definition(to_offset(document.text(), position.position))?;
+        Some(GotoDefinitionResponse::Scalar(Location::new(
+            position.text_document.uri,
+            to_range(document.text(), symbol.name_span),
+        )))
+    }
+
+    fn symbols(&self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let document = self.document(&params.text_document.uri)?;
+        let symbols = document
+            .symbols()
+            .iter()
+            .map(|symbol| {
+                #[allow(deprecated)]
+                DocumentSymbol {
+                    name: symbol.name().to_string(),
+                    detail: Some(symbol.signature()),
+                    kind: SymbolKind::FUNCTION,
+                    tags: None,
+                    deprecated: None,
+                    range: to_range(document.text(), symbol.span),
+                    selection_range: to_range(document.text(), symbol.name_span),
+                    children: None,
+                }
+            })
+            .collect();
+        Some(DocumentSymbolResponse::Nested(symbols))
+    }
+}
+
+// request 是 R 时处理它并返回响应, 否则返回 None
+fn respond<R: lsp_types::request::Request>(
+    request: &Request,
+    handle: impl FnOnce(R::Params) -> R::Result,
+) -> Option<Response> {
+    if request.method != R::METHOD {
+        return None;
+    }
+    Some(match serde_json::from_value(request.params.clone()) {
+        Ok(params) => Response::new_ok(request.id.clone(), handle(params)),
+        Err(e) => Response::new_err(
+            request.id.clone(),
+            ErrorCode::InvalidParams as i32,
+            e.to_string(),
+        ),
+    })
+}
+
+// Serves LSP over stdin/stdout until the client shuts the server down.
+pub fn run_stdio() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+    connection.initialize(serde_json::to_value(Server::capabilities())?)?;
+    let mut server = Server::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                let response = server.handle_request(&request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                if let Some(publish) = server.handle_notification(notification) {
+                    connection.sender.send(Message::Notification(publish))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_lsp {
+    use super::*;
+    use lsp_types::request::Request as _;
+    use serde_json::json;
+    use std::str::FromStr;
+
+    const SOURCE: &str = "extern sin(a);\ndef f(x y)\n  x + sin(y);\nf(1, 2) + g(3);\nf(1)";
+
+    #[test]
+    fn test_document() {
+        let document = Document::new(SOURCE.to_string());
+        let names: Vec<_> = document.symbols().iter().map(Symbol::signature).collect();
+        assert_eq!(names, ["extern sin(a)", "def f(x y)"]);
+        assert_eq!(document.symbols()[1].name_span, Span::new(19, 20));
+        assert_eq!(document.symbols()[1].span, Span::new(15, 38));
+
+        let diagnostics: Vec<_> = document
+            .diagnostics()
+            .iter()
+            .map(|(span, message)| (&SOURCE[span.start..span.end], message.as_str()))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                ("g", "call to undeclared function:g"),
+                ("f", "function f expects 2 argument(s), but 1 were given"),
+            ]
+        );
+
+        // 调用处跳到定义; 变量不是函数
+        let call = SOURCE.find("f(1, 2)").unwrap();
+        assert_eq!(
+            document.definition(call).unwrap().name_span,
+            Span::new(19, 20)
+        );
+        let sin = SOURCE.find("sin(y)").unwrap() + 2;
+        assert!(document.definition(sin).unwrap().is_extern);
+        assert!(document.definition(SOURCE.find("x +").unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_parse_diagnostics() {
+        let document = Document::new("def f(x x\n1 2 3".to_string());
+        let spans: Vec<_> = document
+            .diagnostics()
+            .iter()
+            .map(|(span, _)| *span)
+            .collect();
+        // 'x' 之后缺少 ')', 跳过 1 后 2 和 3 之间缺少 ';'
+        assert_eq!(spans, [Span::new(10, 11), Span::new(14, 15)]);
+    }
+
+    #[test]
+    fn test_positions() {
+        let text = "ab\n变量 x\n";
+        assert_eq!(to_position(text, 0), Position::new(0, 0));
+        assert_eq!(to_position(text, 3), Position::new(1, 0));
+        assert_eq!(to_position(text, 10), Position::new(1, 3));
+        assert_eq!(to_offset(text, Position::new(1, 3)), 10);
+        assert_eq!(to_offset(text, Position::new(1, 100)), 11);
+        assert_eq!(to_offset(text, Position::new(5, 0)), text.len());
+    }
+
+    #[test]
+    fn test_server() {
+        let uri = Uri::from_str("file:///test.k").unwrap();
+        let mut server = Server::new();
+        let open = Notification::new(
+            DidOpenTextDocument::METHOD.to_string(),
+            json!({
+                "textDocument": {"uri": "file:///test.k", "languageId": "kaleidoscope", "version": 1, "text": SOURCE}
+            }),
+        );
+        let publish = server.handle_notification(open).unwrap();
+        assert_eq!(publish.method, PublishDiagnostics::METHOD);
+        assert_eq!(publish.params["diagnostics"].as_array().unwrap().len(), 2);
+
+        let position = json!({"textDocument": {"uri": "file:///test.k"}, "position": {"line": 3, "character": 0}});
+        let hover = server.handle_request(&Request::new(
+            1.into(),
+            HoverRequest::METHOD.to_string(),
+            position.clone(),
+        ));
+        assert_eq!(
+            hover.result.unwrap()["contents"]["value"],
+            "```kaleidoscope\ndef f(x y)\n```"
+        );
+        let definition = server.handle_request(&Request::new(
+            2.into(),
+            GotoDefinition::METHOD.to_string(),
+            position,
+        ));
+        assert_eq!(
+            definition.result.unwrap()["range"],
+            json!({"start": {"line": 1, "character": 4}, "end": {"line": 1, "character": 5}})
+        );
+        let symbols = server.handle_request(&Request::new(
+            3.into(),
+            DocumentSymbolRequest::METHOD.to_string(),
+            json!({"textDocument": {"uri": "file:///test.k"}}),
+        ));
+        assert_eq!(symbols.result.unwrap().as_array().unwrap().len(), 2);
+
+        let change = Notification::new(
+            DidChangeTextDocument::METHOD.to_string(),
+            json!({
+                "textDocument": {"uri": "file:///test.k", "version": 2},
+                "contentChanges": [{"text": "def f(x) x"}]
+            }),
+        );
+        let publish = server.handle_notification(change).unwrap();
+        assert_eq!(publish.params["diagnostics"], json!([]));
+        assert_eq!(server.document(&uri).unwrap().text(), "def f(x) x");
+
+        let unknown =
+            server.handle_request(&Request::new(4.into(), "foo".to_string(), json!(null)));
+        assert!(unknown.error.is_some());
+    }
+}
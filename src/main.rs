@@ -1,3 +1,392 @@
-fn main() {
-    println!("Hello, world!");
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::process::ExitCode;
+
+use colored::Colorize;
+use kaleidoscope::dot::program_to_dot;
+use kaleidoscope::format::format_source;
+use kaleidoscope::report::{Report, check_source};
+use kaleidoscope::stream::StreamParser;
+use kaleidoscope::trace::trace_source;
+use kaleidoscope::{ParseError, TopLevelItem, parse_str};
+
+const USAGE: &str = "usage: kaleidoscope [--error-format=human|json] \
+     [fmt [--check] [file...] | check <file> | trace <file> [--out <path>] | dot <file> | lsp]";
+
+// 错误信息的输出格式, json 时每个错误输出一行 JSON 对象
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum ErrorFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+fn main() -> ExitCode {
+    apply_color_env();
+    let defaults = match default_options() {
+        Ok(defaults) => defaults,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // 命令行上的 --error-format 优先于 KALEIDOSCOPE_OPTS
+    let error_format = match take_error_format(&mut args) {
+        Ok(error_format) => error_format.or(defaults.error_format).unwrap_or_default(),
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    if defaults.check && args.first().is_some_and(|arg| arg == "fmt") {
+        args.insert(1, "--check".to_string());
+    }
+    match args.first().map(String::as_str) {
+        None => {
+            repl(error_format);
+            ExitCode::SUCCESS
+        }
+        Some("fmt") => fmt(&args[1..], error_format),
+        Some("check") => check(&args[1..], error_format),
+        Some("trace") => trace(&args[1..]),
+        Some("dot") => dot(&args[1..], error_format),
+        Some("lsp") => lsp(),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// --error-format=human|json 可以出现在任何位置, 取出后不再作为参数.
+// 没有给出时返回 None
+fn take_error_format(args: &mut Vec<String>) -> Result<Option<ErrorFormat>, String> {
+    let mut error_format = None;
+    let mut error = None;
+    args.retain(|arg| match arg.strip_prefix("--error-format=") {
+        Some("human") => {
+            error_format = Some(ErrorFormat::Human);
+            false
+        }
+        Some("json") if cfg!(not(feature = "json")) => {
+            error = Some("kaleidoscope was built without the `json` feature".to_string());
+            false
+        }
+        Some("json") => {
+            error_format = Some(ErrorFormat::Json);
+            false
+        }
+        Some(other) => {
+            error = Some(format!("unknown error format {}: expected human or json", other));
+            false
+        }
+        None => true,
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok(error_format),
+    }
+}
+
+// KALEIDOSCOPE_OPTS 中用空白分隔的默认选项
+#[derive(Debug, Default)]
+struct DefaultOptions {
+    error_format: Option<ErrorFormat>, // --error-format=human|json
+    check: bool,                       // --check, 只对 fmt 有效
+}
+
+fn default_options() -> Result<DefaultOptions, String> {
+    let Ok(opts) = env::var("KALEIDOSCOPE_OPTS") else {
+        return Ok(DefaultOptions::default());
+    };
+    let mut opts: Vec<String> = opts.split_whitespace().map(String::from).collect();
+    let error_format =
+        take_error_format(&mut opts).map_err(|e| format!("KALEIDOSCOPE_OPTS: {}", e))?;
+    let mut check = false;
+    for opt in opts {
+        match opt.as_str() {
+            "--check" => check = true,
+            _ => return Err(format!("KALEIDOSCOPE_OPTS: unknown option {}", opt)),
+        }
+    }
+    Ok(DefaultOptions { error_format, check })
+}
+
+// KALC_COLOR=always|never|auto 控制错误信息是否着色, auto(默认)时由终端和 NO_COLOR 决定
+fn apply_color_env() {
+    match env::var("KALC_COLOR").as_deref() {
+        Ok("always") => colored::control::set_override(true),
+        Ok("never") => colored::control::set_override(false),
+        Ok("auto") | Ok("") | Err(_) => {}
+        Ok(other) => eprintln!("ignoring KALC_COLOR={}: expected always, never or auto", other),
+    }
+}
+
+// fmt [--check] [file...]
+// 没有给出文件时从 stdin 读入, 结果写到 stdout.
+// --check 只检查不修改, 有文件需要格式化时返回非零
+fn fmt(args: &[String], error_format: ErrorFormat) -> ExitCode {
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if let Some(arg) = paths.iter().find(|arg| arg.starts_with('-')) {
+        eprintln!("unknown option {}\n{}", arg, USAGE);
+        return ExitCode::FAILURE;
+    }
+
+    let mut ok = true;
+    if paths.is_empty() {
+        let mut source = String::new();
+        if let Err(e) = io::stdin().read_to_string(&mut source) {
+            eprintln!("{}", format!("Error: {}", e).red());
+            return ExitCode::FAILURE;
+        }
+        match format_source(&source) {
+            Ok(formatted) if check => {
+                if formatted != source {
+                    println!("<stdin> is not formatted");
+                    ok = false;
+                }
+            }
+            Ok(formatted) => print!("{}", formatted),
+            Err(errors) => {
+                report_errors("<stdin>", &errors, error_format);
+                ok = false;
+            }
+        }
+    }
+    for path in paths {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("{}", format!("Error: {}: {}", path, e).red());
+                ok = false;
+                continue;
+            }
+        };
+        match format_source(&source) {
+            Ok(formatted) if formatted == source => {}
+            Ok(_) if check => {
+                println!("{} is not formatted", path);
+                ok = false;
+            }
+            Ok(formatted) => {
+                if let Err(e) = fs::write(path, formatted) {
+                    eprintln!("{}", format!("Error: {}: {}", path, e).red());
+                    ok = false;
+                }
+            }
+            Err(errors) => {
+                report_errors(path, &errors, error_format);
+                ok = false;
+            }
+        }
+    }
+    if ok { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+// check <file>
+// 解析并做语义检查, 报告全部错误; 有错误时返回非零
+fn check(args: &[String], error_format: ErrorFormat) -> ExitCode {
+    let [path] = args else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}", format!("Error: {}: {}", path, e).red());
+            return ExitCode::FAILURE;
+        }
+    };
+    let reports = check_source(&source);
+    for report in &reports {
+        print_report(path, report, error_format);
+    }
+    if reports.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+// trace <file> [--out <path>]
+// 把各阶段的记录以 JSON 输出到 stdout 或 --out 指定的文件
+fn trace(args: &[String]) -> ExitCode {
+    let (path, out) = match args {
+        [path] => (path, None),
+        [path, flag, out] if flag == "--out" => (path, Some(out)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}", format!("Error: {}: {}", path, e).red());
+            return ExitCode::FAILURE;
+        }
+    };
+    let json = trace_source(&source).to_json();
+    match out {
+        Some(out) => {
+            if let Err(e) = fs::write(out, json + "\n") {
+                eprintln!("{}", format!("Error: {}: {}", out, e).red());
+                return ExitCode::FAILURE;
+            }
+        }
+        None => println!("{}", json),
+    }
+    ExitCode::SUCCESS
+}
+
+// dot <file>
+// 把文件的 AST 以 Graphviz 格式输出到 stdout, 例如 `kaleidoscope dot a.k | dot -Tsvg`
+fn dot(args: &[String], error_format: ErrorFormat) -> ExitCode {
+    let [path] = args else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}", format!("Error: {}: {}", path, e).red());
+            return ExitCode::FAILURE;
+        }
+    };
+    match parse_str(&source) {
+        Ok(program) => {
+            print!("{}", program_to_dot(&program));
+            ExitCode::SUCCESS
+        }
+        Err(errors) => {
+            report_errors(path, &errors, error_format);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// 通过 stdin/stdout 提供语言服务
+#[cfg(feature = "lsp")]
+fn lsp() -> ExitCode {
+    match kaleidoscope::lsp::run_stdio() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", format!("Error: {}", e).red());
+            ExitCode::FAILURE
+        }
+    }
+}
+#[cfg(not(feature = "lsp"))]
+fn lsp() -> ExitCode {
+    eprintln!("kaleidoscope was built without the `lsp` feature");
+    ExitCode::FAILURE
+}
+
+fn report_errors(path: &str, errors: &[ParseError], error_format: ErrorFormat) {
+    for error in errors {
+        print_report(path, &Report::parse(error), error_format);
+    }
+}
+
+fn print_report(path: &str, report: &Report, error_format: ErrorFormat) {
+    match error_format {
+        ErrorFormat::Human => eprintln!("{}", report.to_text(path).red()),
+        ErrorFormat::Json => eprintln!("{}", to_json(path, report)),
+    }
+}
+
+#[cfg(feature = "json")]
+fn to_json(path: &str, report: &Report) -> String {
+    report.to_json(path)
+}
+// take_error_format 不接受 json
+#[cfg(not(feature = "json"))]
+fn to_json(_: &str, _: &Report) -> String {
+    unreachable!("kaleidoscope was built without the `json` feature")
+}
+
+fn repl(error_format: ErrorFormat) {
+    let stdin = io::stdin();
+    // 整个会话共用一个解析器, 未完成的顶层项等下一行输入
+    let mut parser = StreamParser::lines();
+    let mut summary = Summary {
+        error_format,
+        ..Summary::default()
+    };
+    loop {
+        let prompt = if parser.is_pending() { "...> " } else { "ready> " };
+        print!("{}", prompt);
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("{}", format!("Error: {}", e).red());
+                break;
+            }
+        }
+        parser.push(line.as_bytes());
+        while let Some(item) = parser.next_item() {
+            summary.handle_item(item);
+        }
+    }
+
+    // EOF (Ctrl-D): 换行结束提示符, 处理剩余的输入后输出统计
+    println!();
+    parser.finish();
+    while let Some(item) = parser.next_item() {
+        summary.handle_item(item);
+    }
+    println!("{}", summary);
+}
+
+// 本次会话中处理过的顶层项个数
+#[derive(Debug, Default)]
+struct Summary {
+    definitions: usize,
+    externs: usize,
+    globals: usize,
+    expressions: usize,
+    errors: usize,
+    error_format: ErrorFormat,
+}
+impl Summary {
+    fn handle_item(&mut self, item: Result<TopLevelItem, ParseError>) {
+        match item {
+            Ok(TopLevelItem::Def(_)) => {
+                self.definitions += 1;
+                println!("Parsed a function definition.");
+            }
+            Ok(TopLevelItem::Extern(_)) => {
+                self.externs += 1;
+                println!("Parsed an extern.");
+            }
+            Ok(TopLevelItem::Global(_)) => {
+                self.globals += 1;
+                println!("Parsed a global variable.");
+            }
+            Ok(TopLevelItem::Expr(_)) => {
+                self.expressions += 1;
+                println!("Parsed a top-level expr.");
+            }
+            Err(error) => {
+                self.errors += 1;
+                match self.error_format {
+                    ErrorFormat::Human => eprintln!("{}", Report::parse(&error).to_text("<stdin>").red()),
+                    ErrorFormat::Json => eprintln!("{}", to_json("<stdin>", &Report::parse(&error))),
+                }
+            }
+        }
+    }
+}
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Parsed {} function definition(s), {} extern(s), {} global(s), {} top-level expr(s); {} error(s).",
+            self.definitions, self.externs, self.globals, self.expressions, self.errors
+        )
+    }
 }
@@ -0,0 +1,210 @@
+use std::fmt::{self, Display};
+
+use crate::sema::{self, analyze_each};
+use crate::{ASTParser, Lexer, ParseError, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+// One diagnostic in the form tools consume: a stable code such as "K0102",
+// a severity, the byte range it points at (None when the error has no
+// position, e.g. a syntax error built without one) and the human-readable
+// message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub code: &'static str,
+    pub severity: Severity,
+    pub span: Option<Span>,
+    pub message: String,
+}
+impl Report {
+    pub fn parse(error: &ParseError) -> Self {
+        Report {
+            code: error.code(),
+            severity: Severity::Error,
+            span: error.span(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn semantic(diagnostic: &sema::Diagnostic) -> Self {
+        Report {
+            code: diagnostic.code(),
+            severity: Severity::Error,
+            span: diagnostic.span,
+            message: diagnostic.to_string(),
+        }
+    }
+
+    // 给人看的一行, 例如 error[K0102] a.k:4..5: expected ..., 没有位置时省略范围
+    pub fn to_text(&self, file: &str) -> String {
+        match self.span {
+            Some(span) => format!(
+                "{}[{}] {}:{}: {}",
+                self.severity, self.code, file, span, self.message
+            ),
+            None => format!(
+                "{}[{}] {}: {}",
+                self.severity, self.code, file, self.message
+            ),
+        }
+    }
+
+    // 一行一个 JSON 对象, 例如
+    // {"code":"K0102","severity":"error","file":"a.k","span":{"start":4,"end":5},"message":"..."}
+    #[cfg(feature = "json")]
+    pub fn to_json(&self, file: &str) -> String {
+        let span = self
+            .span
+            .map(|span| serde_json::json!({ "start": span.start, "end": span.end }));
+        let json = serde_json::json!({
+            "code": self.code,
+            "severity": self.severity.to_string(),
+            "file": file,
+            "span": span,
+            "message": self.message,
+        });
+        json.to_string()
+    }
+}
+
+// Parses the whole source, recovering from errors and checking separators
+// like `ASTParser::parse_program`, and runs semantic analysis on the items
+// that parsed, like the language server does. Reports are sorted by
+// position; those without one come last.
+pub fn check_source(source: &str) -> Vec<Report> {
+    let mut parser = ASTParser::new(Lexer::from_str(source));
+    parser.update_token();
+    let mut reports = Vec::new();
+    let mut items = Vec::new();
+    while let Some((item, _)) = parser.parse_next_item() {
+        match item {
+            Ok(item) => items.push(item),
+            Err(error) => reports.push(Report::parse(&error)),
+        }
+    }
+    let diagnostics = analyze_each(&items);
+    reports.extend(diagnostics.iter().flatten().map(Report::semantic));
+    reports.sort_by_key(|report| report.span.map_or(usize::MAX, |span| span.start));
+    reports
+}
+
+#[cfg(test)]
+mod test_report {
+    use super::*;
+    use crate::{LexError, Token};
+
+    #[test]
+    fn test_check_source() {
+        let reports = check_source("def f(x) y;\nf(1, 2);\n1 + 1.2.3;\ng(");
+        let codes: Vec<_> = reports.iter().map(|report| report.code).collect();
+        // y 未定义, 参数个数不对, 数字格式错误, 输入在调用中途结束
+        assert_eq!(codes, ["K0201", "K0203", "K0001", "K0103"]);
+        let spans: Vec<_> = reports.iter().map(|report| report.span).collect();
+        let expected = [(9, 10), (12, 13), (25, 30), (34, 34)];
+        assert_eq!(
+            spans,
+            expected.map(|(start, end)| Some(Span::new(start, end)))
+        );
+        assert!(
+            reports
+                .iter()
+                .all(|report| report.severity == Severity::Error)
+        );
+        assert_eq!(
+            reports[1].message,
+            "function f expects 1 argument(s), but 2 were given"
+        );
+
+        assert_eq!(check_source("def f(x) x; f(2)"), []);
+        let reports = check_source("def f(x) x f(2)");
+        assert_eq!(reports.len(), 1);
+        assert_eq!(
+            (reports[0].code, reports[0].span),
+            ("K0102", Some(Span::new(11, 12)))
+        );
+    }
+
+    #[test]
+    fn test_codes() {
+        let span = Span::new(0, 1);
+        let lex_errors = [
+            LexError::MalformedNumber("1.2.3".to_string(), span),
+            LexError::TokenTooLong(span, 1),
+            LexError::Io(std::io::ErrorKind::Other, "broken".to_string(), span),
+            LexError::InvalidUtf8(span),
+        ];
+        let parse_errors = [
+            ParseError::SyntaxError(String::new()),
+            ParseError::UnexpectedToken(Token::Comma, vec![], span),
+            ParseError::UnexpectedEof(vec![], span),
+            ParseError::GeneralError(String::new()),
+        ];
+        let codes: Vec<_> = lex_errors
+            .iter()
+            .map(LexError::code)
+            .chain(parse_errors.iter().map(ParseError::code))
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                "K0001", "K0002", "K0003", "K0004", "K0101", "K0102", "K0103", "K0104"
+            ]
+        );
+        // 词法错误在 parser 中保持原来的编号和位置
+        let error = ParseError::LexerError(lex_errors[3].clone());
+        assert_eq!((error.code(), error.span()), ("K0004", Some(span)));
+    }
+
+    #[test]
+    fn test_to_text() {
+        let error =
+            ParseError::UnexpectedToken(Token::RParen, vec![Token::Number], Span::new(4, 5));
+        assert_eq!(
+            Report::parse(&error).to_text("a.k"),
+            "error[K0102] a.k:4..5: expected Number, got ')' at 4..5"
+        );
+        let error = ParseError::SyntaxError("bad".to_string());
+        assert_eq!(
+            Report::parse(&error).to_text("a.k"),
+            "error[K0101] a.k: Syntax error:bad"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_to_json() {
+        let report = Report {
+            code: "K0101",
+            severity: Severity::Error,
+            span: Some(Span::new(4, 9)),
+            message: "unknown type `int` \"quoted\"\n".to_string(),
+        };
+        assert_eq!(
+            report.to_json("dir\\a.k"),
+            r#"{"code":"K0101","severity":"error","file":"dir\\a.k","span":{"start":4,"end":9},"message":"unknown type `int` \"quoted\"\n"}"#
+        );
+        let report = Report::parse(&ParseError::UnexpectedEof(
+            vec![Token::RParen],
+            Span::new(2, 2),
+        ));
+        assert_eq!(
+            report.to_json("<stdin>"),
+            r#"{"code":"K0103","severity":"error","file":"<stdin>","span":{"start":2,"end":2},"message":"unexpected end of input, expected ')'"}"#
+        );
+        let report = Report::parse(&ParseError::SyntaxError("\u{1}".to_string()));
+        assert_eq!(
+            report.to_json("<stdin>"),
+            r#"{"code":"K0101","severity":"error","file":"<stdin>","span":null,"message":"Syntax error:\u0001"}"#
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::PrototypeAST;
+
+// Standard Kaleidoscope helper functions, callable through the C ABI.
+// Every Kaleidoscope value is a double, so every helper takes and returns f64;
+// `NativeFn` tells the helpers apart by their number of parameters.
+#[derive(Debug, Clone, Copy)]
+pub enum NativeFn {
+    Unary(extern "C" fn(f64) -> f64),
+    Binary(extern "C" fn(f64, f64) -> f64),
+}
+impl NativeFn {
+    pub fn arity(&self) -> usize {
+        match self {
+            NativeFn::Unary(_) => 1,
+            NativeFn::Binary(_) => 2,
+        }
+    }
+    // None when the number of arguments doesn't match the arity
+    pub fn call(&self, args: &[f64]) -> Option<f64> {
+        match (self, args) {
+            (NativeFn::Unary(func), [x]) => Some(func(*x)),
+            (NativeFn::Binary(func), [x, y]) => Some(func(*x, *y)),
+            _ => None,
+        }
+    }
+}
+
+// putchard - putchar that takes a double and returns 0.
+pub extern "C" fn putchard(x: f64) -> f64 {
+    let mut stderr = io::stderr();
+    let _ = stderr.write_all(&[x as u8]);
+    let _ = stderr.flush();
+    0.0
+}
+
+// printd - printf that takes a double and prints it as "%f\n", returning 0.
+pub extern "C" fn printd(x: f64) -> f64 {
+    eprintln!("{:.6}", x);
+    0.0
+}
+
+pub extern "C" fn sin(x: f64) -> f64 {
+    x.sin()
+}
+
+pub extern "C" fn cos(x: f64) -> f64 {
+    x.cos()
+}
+
+pub extern "C" fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+pub extern "C" fn log(x: f64) -> f64 {
+    x.ln()
+}
+
+pub extern "C" fn pow(x: f64, y: f64) -> f64 {
+    x.powf(y)
+}
+
+pub extern "C" fn atan2(y: f64, x: f64) -> f64 {
+    y.atan2(x)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub func: NativeFn,
+}
+
+// I/O helpers from the tutorial, always available.
+pub const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "putchard",
+        func: NativeFn::Unary(putchard),
+    },
+    Builtin {
+        name: "printd",
+        func: NativeFn::Unary(printd),
+    },
+];
+
+// Math functions bridged to Rust's f64 methods, so no libm has to be loaded:
+//
+//   extern          | computes
+//   ----------------+------------------------------
+//   sin(x)          | x.sin()
+//   cos(x)          | x.cos()
+//   exp(x)          | x.exp()
+//   log(x)          | x.ln()   (natural logarithm)
+//   pow(x y)        | x.powf(y)
+//   atan2(y x)      | y.atan2(x)
+//
+// Bridging can be switched off with `Runtime::set_math_bridging(false)`.
+pub const MATH_FUNCTIONS: &[Builtin] = &[
+    Builtin {
+        name: "sin",
+        func: NativeFn::Unary(sin),
+    },
+    Builtin {
+        name: "cos",
+        func: NativeFn::Unary(cos),
+    },
+    Builtin {
+        name: "exp",
+        func: NativeFn::Unary(exp),
+    },
+    Builtin {
+        name: "log",
+        func: NativeFn::Unary(log),
+    },
+    Builtin {
+        name: "pow",
+        func: NativeFn::Binary(pow),
+    },
+    Builtin {
+        name: "atan2",
+        func: NativeFn::Binary(atan2),
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS
+        .iter()
+        .chain(MATH_FUNCTIONS)
+        .find(|builtin| builtin.name == name)
+}
+
+// Decides which native function an `extern` declaration binds to.
+// Overrides win over the built-in tables.
+#[derive(Debug, Clone)]
+pub struct Runtime {
+    math_bridging: bool,
+    overrides: HashMap<String, NativeFn>,
+}
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime::new()
+    }
+}
+impl Runtime {
+    pub fn new() -> Self {
+        Runtime {
+            math_bridging: true,
+            overrides: HashMap::new(),
+        }
+    }
+    pub fn set_math_bridging(&mut self, enabled: bool) {
+        self.math_bridging = enabled;
+    }
+    pub fn override_extern(&mut self, name: &str, func: NativeFn) {
+        self.overrides.insert(name.to_string(), func);
+    }
+
+    // The declaration only matches when the parameter count agrees as well.
+    // None of the helpers is variadic, so a varargs declaration never matches.
+    pub fn resolve(&self, proto: &PrototypeAST) -> Option<NativeFn> {
+        let name = proto.name.as_str();
+        let func = match self.overrides.get(name) {
+            Some(func) => Some(*func),
+            None => {
+                let mut tables = vec![BUILTINS];
+                if self.math_bridging {
+                    tables.push(MATH_FUNCTIONS);
+                }
+                tables
+                    .into_iter()
+                    .flatten()
+                    .find(|builtin| builtin.name == name)
+                    .map(|builtin| builtin.func)
+            }
+        };
+        func.filter(|func| func.arity() == proto.args.len() && !proto.varargs)
+    }
+}
+
+// Resolves an `extern` declaration against the default runtime library.
+pub fn resolve_extern(proto: &PrototypeAST) -> Option<NativeFn> {
+    Runtime::new().resolve(proto)
+}
+
+#[cfg(test)]
+mod test_runtime {
+    use super::*;
+
+    fn proto(name: &str, args: &[&str]) -> PrototypeAST {
+        PrototypeAST::new(name.to_string(), args.iter().map(|a| (*a).into()).collect())
+    }
+
+    #[test]
+    fn test_builtins() {
+        assert_eq!(putchard(65.0), 0.0);
+        assert_eq!(printd(1.5), 0.0);
+        assert_eq!(sin(0.0), 0.0);
+        assert_eq!(cos(0.0), 1.0);
+        assert_eq!(exp(0.0), 1.0);
+        assert_eq!(log(1.0), 0.0);
+        assert_eq!(pow(2.0, 10.0), 1024.0);
+        assert_eq!(atan2(0.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_resolve_extern() {
+        let func = resolve_extern(&proto("putchard", &["char"])).unwrap();
+        assert_eq!(func.call(&[10.0]), Some(0.0));
+
+        let func = resolve_extern(&proto("pow", &["x", "y"])).unwrap();
+        assert_eq!(func.arity(), 2);
+        assert_eq!(func.call(&[3.0, 2.0]), Some(9.0));
+        assert_eq!(func.call(&[3.0]), None);
+
+        assert!(resolve_extern(&proto("sin", &[])).is_none());
+        assert!(resolve_extern(&proto("sin", &["x"]).with_varargs(true)).is_none());
+        assert!(resolve_extern(&proto("atan2", &["y"])).is_none());
+        assert!(resolve_extern(&proto("foo", &["x"])).is_none());
+    }
+
+    #[test]
+    fn test_math_opt_out() {
+        extern "C" fn my_sin(_: f64) -> f64 {
+            42.0
+        }
+
+        let mut runtime = Runtime::new();
+        runtime.set_math_bridging(false);
+        assert!(runtime.resolve(&proto("cos", &["x"])).is_none());
+        assert!(runtime.resolve(&proto("printd", &["x"])).is_some());
+
+        runtime.override_extern("sin", NativeFn::Unary(my_sin));
+        let func = runtime.resolve(&proto("sin", &["x"])).unwrap();
+        assert_eq!(func.call(&[0.0]), Some(42.0));
+    }
+}
@@ -0,0 +1,762 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::Display;
+use std::iter;
+
+use crate::eval::eval_const;
+use crate::intern::Symbol;
+use crate::{
+    ArrayExprAST, BinaryExprAST, CallExprAST, ClosureExprAST, ExprAST, ExprASTKind, FunctionAST,
+    IndexExprAST, PrototypeAST, Span, TopLevelItem, Type, VariableExprAST, binop_spelling,
+};
+
+// A semantic problem found after parsing succeeded, with the byte range of
+// the expression, parameter or function name it is about. The range is
+// None for nodes that were built by hand rather than parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub span: Option<Span>,
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+impl Diagnostic {
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticKind {
+    UndefinedVariable(String),
+    UndeclaredFunction(String),
+    ArityMismatch {
+        callee: String,
+        expected: usize,
+        found: usize,
+    },
+    // varargs 函数的参数少于固定参数
+    TooFewArguments {
+        callee: String,
+        min: usize,
+        found: usize,
+    },
+    DuplicateParameter {
+        function: String,
+        param: String,
+    },
+    // context 说明出现在哪里, 例如 "argument 1 of f"
+    TypeMismatch {
+        context: String,
+        expected: Type,
+        found: Type,
+    },
+    // 数组字面量的常量下标越界
+    IndexOutOfBounds {
+        index: f64,
+        len: usize,
+    },
+    // 常量下标不是整数
+    NonIntegerIndex(f64),
+}
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::UndefinedVariable(name) => write!(f, "undefined variable:{}", name),
+            DiagnosticKind::UndeclaredFunction(name) => {
+                write!(f, "call to undeclared function:{}", name)
+            }
+            DiagnosticKind::ArityMismatch {
+                callee,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function {} expects {} argument(s), but {} were given",
+                callee, expected, found
+            ),
+            DiagnosticKind::TooFewArguments { callee, min, found } => write!(
+                f,
+                "function {} expects at least {} argument(s), but {} were given",
+                callee, min, found
+            ),
+            DiagnosticKind::DuplicateParameter { function, param } => {
+                write!(f, "duplicate parameter {} in function {}", param, function)
+            }
+            DiagnosticKind::TypeMismatch {
+                context,
+                expected,
+                found,
+            } => write!(
+                f,
+                "type mismatch in {}: expected {}, found {}",
+                context, expected, found
+            ),
+            DiagnosticKind::IndexOutOfBounds { index, len } => write!(
+                f,
+                "index {} is out of bounds for an array of length {}",
+                index, len
+            ),
+            DiagnosticKind::NonIntegerIndex(index) => {
+                write!(f, "array index {} is not an integer", index)
+            }
+        }
+    }
+}
+impl DiagnosticKind {
+    // 稳定的错误码, 新的诊断只能追加新的编号
+    pub fn code(&self) -> &'static str {
+        match self {
+            DiagnosticKind::UndefinedVariable(_) => "K0201",
+            DiagnosticKind::UndeclaredFunction(_) => "K0202",
+            DiagnosticKind::ArityMismatch { .. } => "K0203",
+            DiagnosticKind::TooFewArguments { .. } => "K0204",
+            DiagnosticKind::DuplicateParameter { .. } => "K0205",
+            DiagnosticKind::TypeMismatch { .. } => "K0206",
+            DiagnosticKind::IndexOutOfBounds { .. } => "K0207",
+            DiagnosticKind::NonIntegerIndex(_) => "K0208",
+        }
+    }
+}
+
+// Symbol table: function name -> parameter types and return type.
+// Names become visible in source order, like in the REPL; a function can call itself.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    functions: HashMap<Symbol, Signature>,
+}
+#[derive(Debug, Clone)]
+struct Signature {
+    params: Vec<Type>,
+    ret: Type,
+    varargs: bool,
+}
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+    pub fn declare(&mut self, proto: &PrototypeAST) {
+        let signature = Signature {
+            params: proto.arg_types.clone(),
+            ret: proto.return_type,
+            varargs: proto.varargs,
+        };
+        self.functions.insert(proto.name.clone(), signature);
+    }
+    pub fn arity(&self, name: &str) -> Option<usize> {
+        self.signature(name).map(|(args, _)| args.len())
+    }
+    pub fn signature(&self, name: &str) -> Option<(&[Type], Type)> {
+        self.get(name)
+            .map(|signature| (signature.params.as_slice(), signature.ret))
+    }
+    pub fn is_varargs(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|signature| signature.varargs)
+    }
+    fn get(&self, name: &str) -> Option<&Signature> {
+        self.functions.get(name)
+    }
+}
+
+// Variables `function` reads without binding them, in order of first use.
+// For a local function these are the enclosing parameters it captures
+// (or globals, which don't need capturing).
+pub fn free_variables(function: &FunctionAST) -> Vec<Symbol> {
+    let mut bound = function.proto.args.clone();
+    let mut free = Vec::new();
+    collect_free(function.body.as_ref(), &mut bound, &mut free);
+    free
+}
+
+fn collect_free(expr: &dyn ExprAST, bound: &mut Vec<Symbol>, free: &mut Vec<Symbol>) {
+    match expr.kind() {
+        ExprASTKind::Variable => {
+            let var = expr.as_any().downcast_ref::<VariableExprAST>().unwrap();
+            if !bound.contains(&var.name) && !free.contains(&var.name) {
+                free.push(var.name.clone());
+            }
+        }
+        ExprASTKind::Binary => {
+            let binary = expr.as_any().downcast_ref::<BinaryExprAST>().unwrap();
+            collect_free(binary.lhs.as_ref(), bound, free);
+            collect_free(binary.rhs.as_ref(), bound, free);
+        }
+        ExprASTKind::Call => {
+            let call = expr.as_any().downcast_ref::<CallExprAST>().unwrap();
+            for arg in &call.args {
+                collect_free(arg.as_ref(), bound, free);
+            }
+        }
+        ExprASTKind::Array => {
+            let array = expr.as_any().downcast_ref::<ArrayExprAST>().unwrap();
+            for element in &array.elements {
+                collect_free(element.as_ref(), bound, free);
+            }
+        }
+        ExprASTKind::Index => {
+            let index = expr.as_any().downcast_ref::<IndexExprAST>().unwrap();
+            collect_free(index.array.as_ref(), bound, free);
+            collect_free(index.index.as_ref(), bound, free);
+        }
+        ExprASTKind::Closure => {
+            let closure = expr.as_any().downcast_ref::<ClosureExprAST>().unwrap();
+            let function = &closure.function;
+            let outer = bound.len();
+            bound.extend_from_slice(&function.proto.args);
+            collect_free(function.body.as_ref(), bound, free);
+            bound.truncate(outer);
+            collect_free(closure.body.as_ref(), bound, free);
+        }
+        ExprASTKind::Number
+        | ExprASTKind::Prototype
+        | ExprASTKind::Global
+        | ExprASTKind::Function
+        | ExprASTKind::Error
+        | ExprASTKind::Empty => {}
+    }
+}
+
+// Walks top-level items (function definitions, externs and top-level expressions)
+// and reports every problem found.
+pub fn analyze(items: &[TopLevelItem]) -> Vec<Diagnostic> {
+    analyze_each(items).into_iter().flatten().collect()
+}
+
+// Same as `analyze`, with the diagnostics grouped by the item they come from.
+pub fn analyze_each(items: &[TopLevelItem]) -> Vec<Vec<Diagnostic>> {
+    let mut analyzer = Analyzer {
+        symbols: SymbolTable::new(),
+        globals: HashMap::new(),
+        diagnostics: Vec::new(),
+    };
+    items
+        .iter()
+        .map(|item| {
+            analyzer.check_item(item);
+            std::mem::take(&mut analyzer.diagnostics)
+        })
+        .collect()
+}
+
+struct Analyzer {
+    symbols: SymbolTable,
+    globals: HashMap<Symbol, Type>, // 已定义的全局变量及其类型
+    diagnostics: Vec<Diagnostic>,
+}
+impl Analyzer {
+    fn report(&mut self, kind: DiagnosticKind, span: Option<Span>) {
+        self.diagnostics.push(Diagnostic { kind, span });
+    }
+
+    fn check_item(&mut self, item: &TopLevelItem) {
+        match item {
+            TopLevelItem::Extern(proto) => self.check_prototype(proto),
+            TopLevelItem::Def(function) => self.check_function(function, &HashMap::new()),
+            // 初始值只能引用之前的全局变量
+            TopLevelItem::Global(global) => {
+                let ty = self.check_expr(global.init.as_ref(), &HashMap::new());
+                self.globals.insert(global.name.clone(), ty);
+            }
+            // 顶层表达式包装在匿名函数里, 不需要登记到符号表
+            TopLevelItem::Expr(function) => {
+                self.check_expr(function.body.as_ref(), &HashMap::new());
+            }
+        }
+    }
+
+    // outer 是外层函数的参数, 局部函数可以读取
+    fn check_function(&mut self, function: &FunctionAST, outer: &HashMap<Symbol, Type>) {
+        let proto = &function.proto;
+        self.check_prototype(proto);
+        let mut scope = outer.clone();
+        let params = proto.args.iter().cloned();
+        scope.extend(params.zip(proto.arg_types.iter().copied()));
+        let found = self.check_expr(function.body.as_ref(), &scope);
+        self.check_type(
+            || format!("return value of {}", proto.name),
+            proto.return_type,
+            found,
+            function.body.as_ref(),
+        );
+    }
+
+    // expr 是类型为 found 的表达式
+    fn check_type(
+        &mut self,
+        context: impl FnOnce() -> String,
+        expected: Type,
+        found: Type,
+        expr: &dyn ExprAST,
+    ) {
+        if !expected.accepts(found) {
+            let kind = DiagnosticKind::TypeMismatch {
+                context: context(),
+                expected,
+                found,
+            };
+            self.report(kind, expr.span());
+        }
+    }
+
+    fn check_prototype(&mut self, proto: &PrototypeAST) {
+        let mut seen = HashSet::new();
+        for (i, arg) in proto.args.iter().enumerate() {
+            if !seen.insert(arg.as_str()) {
+                let kind = DiagnosticKind::DuplicateParameter {
+                    function: proto.name.to_string(),
+                    param: arg.to_string(),
+                };
+                self.report(kind, proto.arg_span(i));
+            }
+        }
+        self.symbols.declare(proto);
+    }
+
+    // 返回表达式的类型; 出错的部分按 double 继续检查
+    // 参数遮蔽同名的全局变量
+    fn check_expr(&mut self, expr: &dyn ExprAST, scope: &HashMap<Symbol, Type>) -> Type {
+        match expr.kind() {
+            ExprASTKind::Variable => {
+                let var = expr.as_any().downcast_ref::<VariableExprAST>().unwrap();
+                match scope.get(&var.name).or_else(|| self.globals.get(&var.name)) {
+                    Some(ty) => *ty,
+                    None => {
+                        self.report(
+                            DiagnosticKind::UndefinedVariable(var.name.to_string()),
+                            expr.span(),
+                        );
+                        Type::Double
+                    }
+                }
+            }
+            // 运算数都是 double (bool 按 0 或 1 参与运算), 比较和逻辑运算的结果是 bool
+            ExprASTKind::Binary => {
+                let binary = expr.as_any().downcast_ref::<BinaryExprAST>().unwrap();
+                for operand in [&binary.lhs, &binary.rhs] {
+                    let found = self.check_expr(operand.as_ref(), scope);
+                    self.check_type(
+                        || format!("operand of '{}'", binop_spelling(binary.op)),
+                        Type::Double,
+                        found,
+                        operand.as_ref(),
+                    );
+                }
+                match binary.op {
+                    '<' | '&' | '|' => Type::Bool,
+                    _ => Type::Double,
+                }
+            }
+            ExprASTKind::Call => {
+                let call = expr.as_any().downcast_ref::<CallExprAST>().unwrap();
+                let arg_types: Vec<Type> = call
+                    .args
+                    .iter()
+                    .map(|arg| self.check_expr(arg.as_ref(), scope))
+                    .collect();
+                let Some((params, ret)) = self.symbols.signature(call.callee()) else {
+                    self.report(
+                        DiagnosticKind::UndeclaredFunction(call.callee.to_string()),
+                        call.callee_span(),
+                    );
+                    return Type::Double;
+                };
+                let varargs = self.symbols.is_varargs(call.callee());
+                if varargs && params.len() > call.args.len() {
+                    let kind = DiagnosticKind::TooFewArguments {
+                        callee: call.callee.to_string(),
+                        min: params.len(),
+                        found: call.args.len(),
+                    };
+                    self.report(kind, call.callee_span());
+                    return ret;
+                }
+                if !varargs && params.len() != call.args.len() {
+                    let kind = DiagnosticKind::ArityMismatch {
+                        callee: call.callee.to_string(),
+                        expected: params.len(),
+                        found: call.args.len(),
+                    };
+                    self.report(kind, call.callee_span());
+                    return ret;
+                }
+                // 固定参数之后多出来的参数都是 double
+                let params = params.to_vec();
+                let params = params.into_iter().chain(iter::repeat(Type::Double));
+                for (i, ((expected, found), arg)) in
+                    params.zip(arg_types).zip(&call.args).enumerate()
+                {
+                    self.check_type(
+                        || format!("argument {} of {}", i + 1, call.callee),
+                        expected,
+                        found,
+                        arg.as_ref(),
+                    );
+                }
+                ret
+            }
+            ExprASTKind::Array => {
+                let array = expr.as_any().downcast_ref::<ArrayExprAST>().unwrap();
+                for (i, element) in array.elements.iter().enumerate() {
+                    let found = self.check_expr(element.as_ref(), scope);
+                    self.check_type(
+                        || format!("element {} of array", i + 1),
+                        Type::Double,
+                        found,
+                        element.as_ref(),
+                    );
+                }
+                Type::Array
+            }
+            // 下标必须是整数; 常量下标不是整数时报错, 越界只能在字面量数组时静态发现
+            ExprASTKind::Index => {
+                let index = expr.as_any().downcast_ref::<IndexExprAST>().unwrap();
+                let found = self.check_expr(index.array.as_ref(), scope);
+                let array = index.array.as_ref();
+                self.check_type(|| "indexed value".to_string(), Type::Array, found, array);
+                let found = self.check_expr(index.index.as_ref(), scope);
+                let at = index.index.as_ref();
+                self.check_type(|| "array index".to_string(), Type::Double, found, at);
+                let array = index.array.as_any().downcast_ref::<ArrayExprAST>();
+                let constant = eval_const(index.index.as_ref());
+                if let Some(constant) = constant
+                    && constant.fract() != 0.0
+                {
+                    let kind = DiagnosticKind::NonIntegerIndex(constant);
+                    self.report(kind, index.index.span());
+                } else if let (Some(array), Some(constant)) = (array, constant) {
+                    let len = array.elements.len();
+                    if !(0.0..len as f64).contains(&constant) {
+                        let kind = DiagnosticKind::IndexOutOfBounds {
+                            index: constant,
+                            len,
+                        };
+                        self.report(kind, index.index.span());
+                    }
+                }
+                Type::Double
+            }
+            ExprASTKind::Closure => {
+                let closure = expr.as_any().downcast_ref::<ClosureExprAST>().unwrap();
+                let name = closure.function.proto.name.clone();
+                // 局部函数离开 in 之后的表达式就不可见了, 恢复被遮蔽的同名函数
+                let shadowed = self.symbols.functions.remove(&name);
+                self.check_function(&closure.function, scope);
+                let ty = self.check_expr(closure.body.as_ref(), scope);
+                match shadowed {
+                    Some(signature) => self.symbols.functions.insert(name, signature),
+                    None => self.symbols.functions.remove(&name),
+                };
+                ty
+            }
+            // nested prototypes/functions can't appear inside expressions
+            ExprASTKind::Number
+            | ExprASTKind::Prototype
+            | ExprASTKind::Global
+            | ExprASTKind::Function
+            | ExprASTKind::Error
+            | ExprASTKind::Empty => Type::Double,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_sema {
+    use super::*;
+    use crate::NumberExprAST;
+    use std::sync::Arc;
+
+    fn var(name: &str) -> Arc<dyn ExprAST> {
+        Arc::new(VariableExprAST::new(name.to_string()))
+    }
+    fn proto(name: &str, args: &[&str]) -> Arc<PrototypeAST> {
+        Arc::new(PrototypeAST::new(
+            name.to_string(),
+            args.iter().map(|a| Symbol::from(*a)).collect(),
+        ))
+    }
+    fn call(callee: &str, args: Vec<Arc<dyn ExprAST>>) -> Arc<dyn ExprAST> {
+        Arc::new(CallExprAST::new(callee.to_string(), args))
+    }
+    fn def(proto: Arc<PrototypeAST>, body: Arc<dyn ExprAST>) -> TopLevelItem {
+        TopLevelItem::Def(Arc::new(FunctionAST::new(proto, body)))
+    }
+    fn expr(body: Arc<dyn ExprAST>) -> TopLevelItem {
+        TopLevelItem::Expr(Arc::new(FunctionAST::new(proto("__anon_expr0", &[]), body)))
+    }
+    fn kinds(diagnostics: Vec<Diagnostic>) -> Vec<DiagnosticKind> {
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_program() {
+        // def f(x y) x + y; extern sin(a); f(1, sin(2))
+        let items = vec![
+            def(
+                proto("f", &["x", "y"]),
+                Arc::new(BinaryExprAST::new('+', var("x"), var("y"))),
+            ),
+            TopLevelItem::Extern(proto("sin", &["a"])),
+            expr(call(
+                "f",
+                vec![
+                    Arc::new(NumberExprAST::new(1.0)),
+                    call("sin", vec![Arc::new(NumberExprAST::new(2.0))]),
+                ],
+            )),
+        ];
+        assert_eq!(kinds(analyze(&items)), vec![]);
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let items = vec![def(
+            proto("f", &["x"]),
+            Arc::new(BinaryExprAST::new('*', var("x"), var("y"))),
+        )];
+        assert_eq!(
+            kinds(analyze(&items)),
+            vec![DiagnosticKind::UndefinedVariable("y".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_undeclared_function() {
+        // declared only after use
+        let items = vec![
+            expr(call("g", vec![])),
+            TopLevelItem::Extern(proto("g", &[])),
+        ];
+        assert_eq!(
+            kinds(analyze(&items)),
+            vec![DiagnosticKind::UndeclaredFunction("g".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_recursive_call() {
+        let items = vec![def(proto("fib", &["n"]), call("fib", vec![var("n")]))];
+        assert_eq!(kinds(analyze(&items)), vec![]);
+    }
+
+    #[test]
+    fn test_arity_mismatch() {
+        let items = vec![
+            TopLevelItem::Extern(proto("cos", &["x"])),
+            expr(call(
+                "cos",
+                vec![
+                    Arc::new(NumberExprAST::new(1.0)),
+                    Arc::new(NumberExprAST::new(2.0)),
+                ],
+            )),
+        ];
+        assert_eq!(
+            kinds(analyze(&items)),
+            vec![DiagnosticKind::ArityMismatch {
+                callee: "cos".to_string(),
+                expected: 1,
+                found: 2,
+            }]
+        );
+
+        // varargs 函数至少要有固定参数, 多出来的参数是 double
+        let program = crate::parse_str(
+            "extern printf(fmt, ...); printf(1); printf(1, 2, 3); printf(); printf(1, [2])",
+        )
+        .unwrap();
+        assert_eq!(
+            kinds(analyze(program.items())),
+            vec![
+                DiagnosticKind::TooFewArguments {
+                    callee: "printf".to_string(),
+                    min: 1,
+                    found: 0,
+                },
+                DiagnosticKind::TypeMismatch {
+                    context: "argument 2 of printf".to_string(),
+                    expected: Type::Double,
+                    found: Type::Array,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_analyze_each() {
+        let items = vec![
+            expr(call("g", vec![])),
+            TopLevelItem::Extern(proto("g", &[])),
+            def(proto("f", &["x"]), var("y")),
+        ];
+        assert_eq!(
+            analyze_each(&items)
+                .into_iter()
+                .map(kinds)
+                .collect::<Vec<_>>(),
+            vec![
+                vec![DiagnosticKind::UndeclaredFunction("g".to_string())],
+                vec![],
+                vec![DiagnosticKind::UndefinedVariable("y".to_string())],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_spans() {
+        let source = "def f(x, x) -> bool y; f(1, [2]) + [1][2]; g()";
+        let program = crate::parse_str(source).unwrap();
+        let located: Vec<_> = analyze(program.items())
+            .into_iter()
+            .map(|diagnostic| {
+                let span = diagnostic.span.unwrap();
+                (diagnostic.code(), &source[span.start..span.end])
+            })
+            .collect();
+        // 参数和被调用的函数指向名字, 其它诊断指向出问题的表达式
+        assert_eq!(
+            located,
+            [
+                ("K0205", "x"),
+                ("K0201", "y"),
+                ("K0206", "y"),
+                ("K0206", "[2]"),
+                ("K0207", "2"),
+                ("K0202", "g"),
+            ]
+        );
+        // 手工构造的节点没有位置
+        let items = vec![expr(var("y"))];
+        assert_eq!(analyze(&items)[0].span, None);
+    }
+
+    #[test]
+    fn test_duplicate_parameter() {
+        let items = vec![TopLevelItem::Extern(proto("f", &["x", "x"]))];
+        assert_eq!(
+            kinds(analyze(&items)),
+            vec![DiagnosticKind::DuplicateParameter {
+                function: "f".to_string(),
+                param: "x".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        let program = crate::parse_str(
+            "def pos(x) -> bool 0 < x; def f(x, flag: bool) -> bool flag; \
+             def both(x) -> bool pos(x) && pos(x - 1) || x < 0; \
+             f(1, pos(2)); f(pos(1), 2); def g(x) -> bool x + 1; g(1) + f(1, g(2))",
+        )
+        .unwrap();
+        let mismatch = |context: &str, expected, found| DiagnosticKind::TypeMismatch {
+            context: context.to_string(),
+            expected,
+            found,
+        };
+        assert_eq!(
+            kinds(analyze(program.items())),
+            vec![
+                mismatch("argument 2 of f", Type::Bool, Type::Double),
+                mismatch("return value of g", Type::Bool, Type::Double),
+            ]
+        );
+        assert_eq!(
+            mismatch("argument 2 of f", Type::Bool, Type::Double).to_string(),
+            "type mismatch in argument 2 of f: expected bool, found double"
+        );
+    }
+
+    #[test]
+    fn test_globals() {
+        let program = crate::parse_str(
+            "def early() pi; global pi = 3.14159; global on = 0 < pi; \
+             def area(r) pi * r * r; def f(pi) -> bool pi; def g() -> bool on; global x = y",
+        )
+        .unwrap();
+        assert_eq!(
+            kinds(analyze(program.items())),
+            vec![
+                DiagnosticKind::UndefinedVariable("pi".to_string()),
+                DiagnosticKind::TypeMismatch {
+                    context: "return value of f".to_string(),
+                    expected: Type::Bool,
+                    found: Type::Double,
+                },
+                DiagnosticKind::UndefinedVariable("y".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_closures() {
+        let program = crate::parse_str(
+            "def g(x) x; def f(x, flag: bool) -> bool def g(y) -> bool flag in g(x + y); \
+             g(1, 2); def h(a) def k(b) a + b + c in k(a) + a(1)",
+        )
+        .unwrap();
+        assert_eq!(
+            kinds(analyze(program.items())),
+            vec![
+                DiagnosticKind::UndefinedVariable("y".to_string()),
+                DiagnosticKind::ArityMismatch {
+                    callee: "g".to_string(),
+                    expected: 1,
+                    found: 2,
+                },
+                DiagnosticKind::UndefinedVariable("c".to_string()),
+                DiagnosticKind::UndeclaredFunction("a".to_string()),
+            ]
+        );
+
+        let program =
+            crate::parse_str("def f(x, y) def g(a) def h(b) a + b + x in h(z) + y in g(1)")
+                .unwrap();
+        let TopLevelItem::Def(f) = &program.items()[0] else {
+            panic!("expected a definition");
+        };
+        let g = f.body.as_any().downcast_ref::<ClosureExprAST>().unwrap();
+        assert_eq!(free_variables(&g.function), ["x", "z", "y"]);
+        assert_eq!(free_variables(f), ["z"]);
+    }
+
+    #[test]
+    fn test_arrays() {
+        let program = crate::parse_str(
+            "def first(a: array) a[0]; first([1, 2 < 3]) + [4, 5][1]; \
+             def bad(a: array, x) a + x[a[1]]; [1, 2][1 + 1]; [[1]]; [1, 2][0.5]; def g(a: array) a[0.5 + 1]",
+        )
+        .unwrap();
+        let mismatch = |context: &str, expected, found| DiagnosticKind::TypeMismatch {
+            context: context.to_string(),
+            expected,
+            found,
+        };
+        assert_eq!(
+            kinds(analyze(program.items())),
+            vec![
+                mismatch("operand of '+'", Type::Double, Type::Array),
+                mismatch("indexed value", Type::Array, Type::Double),
+                DiagnosticKind::IndexOutOfBounds { index: 2.0, len: 2 },
+                mismatch("element 1 of array", Type::Double, Type::Array),
+                DiagnosticKind::NonIntegerIndex(0.5),
+                DiagnosticKind::NonIntegerIndex(1.5),
+            ]
+        );
+        assert_eq!(
+            DiagnosticKind::IndexOutOfBounds { index: 2.0, len: 2 }.to_string(),
+            "index 2 is out of bounds for an array of length 2"
+        );
+        assert_eq!(
+            DiagnosticKind::NonIntegerIndex(0.5).to_string(),
+            "array index 0.5 is not an integer"
+        );
+        assert_eq!(DiagnosticKind::NonIntegerIndex(0.5).code(), "K0208");
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::str;
+
+use crate::{ASTParser, CharState, Checkpoint, Lexer, ParseError, Span, Token, TopLevelItem};
+
+// 默认最多为一个未完成的顶层项缓存的字节数
+pub const DEFAULT_MAX_PENDING: usize = 1 << 20;
+
+// Input that arrives in pieces. Reading past the bytes pushed so far ends
+// the input for now and is remembered in `starved`, so the caller can
+// tell a real end of input from one that more input may change.
+#[derive(Debug, Default)]
+pub(crate) struct Chunks {
+    buffer: VecDeque<u8>,
+    partial: Vec<u8>, // 末尾被截断的 UTF-8 字符, 等后续输入补全
+    finished: bool,   // 不会再有输入
+    starved: bool,    // 读到了已有输入的末尾
+}
+impl Chunks {
+    fn push(&mut self, input: &[u8]) {
+        self.partial.extend_from_slice(input);
+        // 只交出完整的字符, 免得截断的字符被读成 U+FFFD
+        let complete = match str::from_utf8(&self.partial) {
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            _ => self.partial.len(),
+        };
+        self.buffer.extend(self.partial.drain(..complete));
+    }
+
+    fn finish(&mut self) {
+        self.buffer.extend(self.partial.drain(..));
+        self.finished = true;
+    }
+}
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() && !self.finished {
+            self.starved = true;
+        }
+        self.buffer.read(buf)
+    }
+}
+
+impl Lexer<Chunks> {
+    pub(crate) fn push(&mut self, input: &[u8]) {
+        self.source.push(input);
+    }
+    pub(crate) fn finish(&mut self) {
+        self.source.finish();
+    }
+    pub(crate) fn is_finished(&self) -> bool {
+        self.source.finished
+    }
+
+    // Starts an attempt to lex or parse with the input pushed so far.
+    pub(crate) fn begin(&mut self) -> Checkpoint {
+        // 上次读到已有输入的末尾后停在了 Eof, 有了新的输入要接着读
+        if self.last_char == CharState::Eof && !self.source.finished {
+            self.last_char = CharState::NotInitailized;
+        }
+        self.source.starved = false;
+        self.checkpoint()
+    }
+
+    // Ends the attempt started by `begin`. It stands if the input is
+    // finished, if the lexer never reached the end of the input pushed so
+    // far, or if `complete` says the result can't change anyway; then
+    // true is returned. Otherwise the lexer goes back to the checkpoint to
+    // try again once more input has arrived. If more than `max_pending`
+    // bytes are waiting for that, they are dropped and their span returned.
+    pub(crate) fn end(
+        &mut self,
+        checkpoint: Checkpoint,
+        complete: bool,
+        max_pending: usize,
+    ) -> Result<bool, Span> {
+        if self.source.finished || !self.source.starved || complete {
+            self.commit(checkpoint);
+            return Ok(true);
+        }
+        self.restore(checkpoint);
+        if self.replay.len() <= max_pending {
+            return Ok(false);
+        }
+        let start = self.token_end();
+        // 从头开始分析之后的输入, 位置仍然从输入的开头算起
+        let end = self.pos + self.replay.len();
+        let mut lexer =
+            Lexer::with_keywords(std::mem::take(&mut self.source), self.keywords.clone()).unwrap();
+        lexer.set_max_token_len(self.max_token_len);
+        (lexer.pos, lexer.char_pos, lexer.tok_start) = (end, end, end);
+        *self = lexer;
+        Err(Span::new(start, end))
+    }
+}
+
+// Parses top-level items from input that arrives in pieces, such as lines
+// typed into the REPL or chunks read from an async stream. One parser is
+// kept for the whole input: anonymous functions are numbered and spans
+// are counted from the start of the input, and only an item that is still
+// waiting for input is lexed again. Errors are recovered from and items
+// separated as in `ASTParser::parse_program`.
+#[derive(Debug)]
+pub struct StreamParser {
+    parser: ASTParser<Chunks>,
+    max_pending: usize,
+    lines: bool,   // 已有输入末尾的完整项立即返回
+    pending: bool, // 有一个项在等待后续输入
+}
+impl StreamParser {
+    // An item is only returned once the token following it has arrived
+    // (or the input is finished), so the input is split exactly as the
+    // blocking parser would split it.
+    pub fn new() -> Self {
+        StreamParser {
+            parser: ASTParser::new(Lexer::new(Chunks::default()).unwrap()),
+            max_pending: DEFAULT_MAX_PENDING,
+            lines: false,
+            pending: false,
+        }
+    }
+
+    // For interactive input: an item that is complete at the end of the
+    // input pushed so far is returned without waiting for the next token,
+    // so `1 + 2` on a line of its own is parsed when the line is entered.
+    pub fn lines() -> Self {
+        StreamParser {
+            lines: true,
+            ..StreamParser::new()
+        }
+    }
+
+    pub fn push(&mut self, input: &[u8]) {
+        self.parser.lexer.push(input);
+    }
+    // 不会再有输入, 未完成的项作为错误返回
+    pub fn finish(&mut self) {
+        self.parser.lexer.finish();
+    }
+    pub fn is_finished(&self) -> bool {
+        self.parser.lexer.is_finished()
+    }
+    // true 时上一次 next_item 停在了一个未完成的项中间
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    // Upper bound in bytes for the input of one item that is still waiting
+    // for more input. Past it the item is dropped and reported as an error.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+    }
+
+    // The next item or error, or None once the input pushed so far has
+    // been used up (for good if the input is finished).
+    pub fn next_item(&mut self) -> Option<Result<TopLevelItem, ParseError>> {
+        let parser = &mut self.parser;
+        let checkpoint = parser.lexer.begin();
+        let (prev_end, anon_count) = (parser.prev_end, parser.anon_count);
+        if matches!(parser.curtok, Token::None | Token::Eof) {
+            parser.update_token();
+        }
+        let item = parser.parse_next_item().map(|(item, _)| item);
+        let complete = match &item {
+            None => false,
+            Some(Ok(_)) => self.lines,
+            // 出错的 token 之后已经读到了字符, 后续输入不会改变它
+            Some(Err(error)) => {
+                !error.is_incomplete()
+                    && error.span().is_some_and(|span| span.end < parser.lexer.pos)
+            }
+        };
+        match parser.lexer.end(checkpoint, complete, self.max_pending) {
+            Ok(true) => {
+                self.pending = false;
+                item
+            }
+            Ok(false) => {
+                parser.curtok = parser.lexer.cur_tok;
+                parser.prev_end = prev_end;
+                parser.anon_count = anon_count;
+                parser.missing_separator = None;
+                self.pending = item.is_some();
+                None
+            }
+            Err(span) => {
+                parser.curtok = Token::None;
+                parser.missing_separator = None;
+                self.pending = false;
+                Some(Err(ParseError::GeneralError(format!(
+                    "input at {} is longer than the limit of {} bytes without completing",
+                    span, self.max_pending
+                ))))
+            }
+        }
+    }
+}
+impl Default for StreamParser {
+    fn default() -> Self {
+        StreamParser::new()
+    }
+}
+
+#[cfg(test)]
+mod test_stream {
+    use super::*;
+    use crate::{Span, format::print_item};
+
+    fn items(parser: &mut StreamParser) -> Vec<Result<String, ParseError>> {
+        std::iter::from_fn(|| parser.next_item())
+            .map(|item| item.map(|item| print_item(&item)))
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_parser() {
+        let mut parser1 = StreamParser::new();
+        // 不需要换行, 读到下一个 token 的开头就可以确定上一项结束
+        parser1.push(b"def f(x) x; 1 +");
+        assert_eq!(items(&mut parser1), [Ok("def f(x)\n    x".to_string())]);
+        assert!(parser1.is_pending());
+        parser1.push(b" 2; def g() 3; ");
+        parser1.push("变".as_bytes().split_at(1).0);
+        let printed = ["1 + 2", "def g()\n    3"].map(|item| Ok(item.to_string()));
+        assert_eq!(items(&mut parser1), printed);
+        assert!(!parser1.is_pending());
+        // 被截断的字符补全后才会被读取
+        parser1.push(&"变".as_bytes()[1..]);
+        assert_eq!(items(&mut parser1), []);
+        assert!(parser1.is_pending());
+        parser1.finish();
+        assert_eq!(items(&mut parser1), [Ok("变".to_string())]);
+        assert!(parser1.is_finished());
+
+        // 匿名函数的编号和位置都从输入的开头算起
+        let mut parser2 = StreamParser::new();
+        for chunk in ["1;", " 2", "; )", " 3"] {
+            parser2.push(chunk.as_bytes());
+        }
+        parser2.finish();
+        let mut results = Vec::new();
+        while let Some(item) = parser2.next_item() {
+            results.push(match item {
+                Ok(TopLevelItem::Expr(anon)) => Ok(anon.proto().name().to_string()),
+                Ok(_) => panic!("expected a top-level expression"),
+                Err(error) => Err(error.span()),
+            });
+        }
+        assert_eq!(
+            results,
+            [
+                Ok("__anon_expr0".to_string()),
+                Ok("__anon_expr1".to_string()),
+                Err(Some(Span::new(6, 7))),
+                Ok("__anon_expr2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stream_separators() {
+        // 和 parse_program 一样, 缺少 ';' 时报错后继续解析
+        let mut parser1 = StreamParser::lines();
+        parser1.push(b"def f(x) x f(2)\n");
+        let results = items(&mut parser1);
+        assert_eq!(results.len(), 3);
+        assert!(matches!(
+            results[1],
+            Err(ParseError::UnexpectedToken(Token::Identifier, _, _))
+        ));
+        assert_eq!(results[2], Ok("f(2)".to_string()));
+
+        // 一行结束时完整的项立即返回, 不完整的等下一行
+        let mut parser2 = StreamParser::lines();
+        parser2.push(b"1 +\n");
+        assert_eq!(items(&mut parser2), []);
+        parser2.push(b"2\n");
+        assert_eq!(items(&mut parser2), [Ok("1 + 2".to_string())]);
+    }
+
+    #[test]
+    fn test_stream_max_pending() {
+        let mut parser1 = StreamParser::new();
+        parser1.set_max_pending(24);
+        parser1.push(b"1; def f(x) x + x + ");
+        assert_eq!(items(&mut parser1).len(), 1);
+        parser1.push(b"x + x + x");
+        let error = parser1.next_item().unwrap().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "error:input at 2..29 is longer than the limit of 24 bytes without completing"
+        );
+        // 之后的输入照常解析, 位置接着原来的算
+        parser1.push(b"; 2");
+        parser1.finish();
+        assert_eq!(items(&mut parser1).len(), 1);
+    }
+}
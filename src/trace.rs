@@ -0,0 +1,226 @@
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::sema::analyze;
+use crate::{
+    ArrayExprAST, BinaryExprAST, CallExprAST, ClosureExprAST, ExprAST, ExprASTKind, FunctionAST,
+    GlobalAST, IndexExprAST, Lexer, Token, TopLevelItem, parse_str,
+};
+
+// What one pipeline stage did: how long it took and a few counts
+// describing its output, e.g. ("tokens", 42) for the lexer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageReport {
+    pub name: &'static str,
+    pub duration: Duration,
+    pub counts: Vec<(&'static str, usize)>,
+}
+impl StageReport {
+    pub fn count(&self, key: &str) -> Option<usize> {
+        self.counts
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, count)| *count)
+    }
+}
+
+// Record of a run through the pipeline, one report per stage in order.
+// The pipeline currently ends after semantic analysis; later stages add
+// their own reports once they exist.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompileTrace {
+    stages: Vec<StageReport>,
+}
+impl CompileTrace {
+    pub fn new() -> Self {
+        CompileTrace::default()
+    }
+    pub fn stages(&self) -> &[StageReport] {
+        &self.stages
+    }
+    pub fn stage(&self, name: &str) -> Option<&StageReport> {
+        self.stages.iter().find(|stage| stage.name == name)
+    }
+
+    // Runs `stage` and records how long it took along with the counts it returns.
+    pub fn record<T>(
+        &mut self,
+        name: &'static str,
+        stage: impl FnOnce() -> (T, Vec<(&'static str, usize)>),
+    ) -> T {
+        let start = Instant::now();
+        let (output, counts) = stage();
+        self.stages.push(StageReport {
+            name,
+            duration: start.elapsed(),
+            counts,
+        });
+        output
+    }
+
+    // {"stages":[{"name":"lex","micros":12,"tokens":42},...]}
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"stages\":[");
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"name\":\"{}\",\"micros\":{}",
+                stage.name,
+                stage.duration.as_micros()
+            )
+            .unwrap();
+            for (key, count) in &stage.counts {
+                write!(json, ",\"{}\":{}", key, count).unwrap();
+            }
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+// Lexes, parses and analyzes `source`, tracing every stage.
+// Stages after a failed parse are skipped.
+pub fn trace_source(source: &str) -> CompileTrace {
+    let mut trace = CompileTrace::new();
+    trace.record("lex", || {
+        let mut lexer = Lexer::from_str(source);
+        let (mut tokens, mut errors) = (0, 0);
+        loop {
+            match lexer.get_token() {
+                Ok(Token::Eof) => break,
+                Ok(_) => tokens += 1,
+                Err(_) => {
+                    tokens += 1;
+                    errors += 1;
+                }
+            }
+        }
+        ((), vec![("tokens", tokens), ("errors", errors)])
+    });
+    let program = trace.record("parse", || match parse_str(source) {
+        Ok(program) => {
+            let items = program.items();
+            let (mut definitions, mut externs, mut globals, mut expressions) = (0, 0, 0, 0);
+            for item in items {
+                match item {
+                    TopLevelItem::Def(_) => definitions += 1,
+                    TopLevelItem::Extern(_) => externs += 1,
+                    TopLevelItem::Global(_) => globals += 1,
+                    TopLevelItem::Expr(_) => expressions += 1,
+                }
+            }
+            let nodes = items
+                .iter()
+                .map(|item| count_nodes(item.as_ast().as_ref()))
+                .sum();
+            let counts = vec![
+                ("items", items.len()),
+                ("definitions", definitions),
+                ("externs", externs),
+                ("globals", globals),
+                ("expressions", expressions),
+                ("nodes", nodes),
+                ("errors", 0),
+            ];
+            (Some(program), counts)
+        }
+        Err(errors) => (None, vec![("errors", errors.len())]),
+    });
+    if let Some(program) = program {
+        trace.record("sema", || {
+            let diagnostics = analyze(program.items());
+            ((), vec![("diagnostics", diagnostics.len())])
+        });
+    }
+    trace
+}
+
+// AST 节点个数, 包括函数的原型
+fn count_nodes(expr: &dyn ExprAST) -> usize {
+    let children = match expr.kind() {
+        ExprASTKind::Binary => {
+            let binary = expr.as_any().downcast_ref::<BinaryExprAST>().unwrap();
+            count_nodes(binary.lhs().as_ref()) + count_nodes(binary.rhs().as_ref())
+        }
+        ExprASTKind::Call => {
+            let call = expr.as_any().downcast_ref::<CallExprAST>().unwrap();
+            call.args()
+                .iter()
+                .map(|arg| count_nodes(arg.as_ref()))
+                .sum()
+        }
+        ExprASTKind::Array => {
+            let array = expr.as_any().downcast_ref::<ArrayExprAST>().unwrap();
+            array
+                .elements()
+                .iter()
+                .map(|element| count_nodes(element.as_ref()))
+                .sum()
+        }
+        ExprASTKind::Index => {
+            let index = expr.as_any().downcast_ref::<IndexExprAST>().unwrap();
+            count_nodes(index.array().as_ref()) + count_nodes(index.index().as_ref())
+        }
+        ExprASTKind::Closure => {
+            let closure = expr.as_any().downcast_ref::<ClosureExprAST>().unwrap();
+            count_nodes(closure.function().as_ref()) + count_nodes(closure.body().as_ref())
+        }
+        ExprASTKind::Global => {
+            let global = expr.as_any().downcast_ref::<GlobalAST>().unwrap();
+            count_nodes(global.init().as_ref())
+        }
+        ExprASTKind::Function => {
+            let function = expr.as_any().downcast_ref::<FunctionAST>().unwrap();
+            1 + count_nodes(function.body().as_ref())
+        }
+        _ => 0,
+    };
+    1 + children
+}
+
+#[cfg(test)]
+mod test_trace {
+    use super::*;
+
+    #[test]
+    fn test_trace_source() {
+        let trace = trace_source("def f(x) x + 1; extern sin(a); f(sin(2))");
+        let names: Vec<_> = trace.stages().iter().map(|stage| stage.name).collect();
+        assert_eq!(names, ["lex", "parse", "sema"]);
+        assert_eq!(trace.stage("lex").unwrap().count("tokens"), Some(22));
+        let parse = trace.stage("parse").unwrap();
+        assert_eq!(parse.count("items"), Some(3));
+        assert_eq!(parse.count("definitions"), Some(1));
+        assert_eq!(parse.count("externs"), Some(1));
+        assert_eq!(parse.count("expressions"), Some(1));
+        assert_eq!(parse.count("globals"), Some(0));
+        // f: 函数 原型 + x 1; sin: 原型; 匿名函数: 函数 原型 f sin 2
+        assert_eq!(parse.count("nodes"), Some(11));
+        assert_eq!(trace.stage("sema").unwrap().count("diagnostics"), Some(0));
+    }
+
+    #[test]
+    fn test_trace_parse_error() {
+        let trace = trace_source("def f(x x");
+        assert_eq!(trace.stages().len(), 2);
+        assert_eq!(trace.stage("parse").unwrap().count("errors"), Some(1));
+        assert!(trace.stage("sema").is_none());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut trace = CompileTrace::new();
+        trace.record("lex", || ((), vec![("tokens", 3)]));
+        trace.stages[0].duration = Duration::from_micros(15);
+        trace.record("sema", || ((), vec![]));
+        trace.stages[1].duration = Duration::from_micros(2);
+        assert_eq!(
+            trace.to_json(),
+            r#"{"stages":[{"name":"lex","micros":15,"tokens":3},{"name":"sema","micros":2}]}"#
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// 通过管道把脚本输入交给 REPL, 返回 (stdout, stderr)
+fn run_repl(input: &str) -> (String, String) {
+    run_repl_with_env(input, &[])
+}
+
+fn run_repl_with_env(input: &str, envs: &[(&str, &str)]) -> (String, String) {
+    let output = run(&[], input, envs);
+    assert!(output.status.success());
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+fn run(args: &[&str], input: &str, envs: &[(&str, &str)]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kaleidoscope"))
+        .args(args)
+        .env("NO_COLOR", "1")
+        .envs(envs.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // 写完后关闭 stdin, REPL 读到 EOF
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_clean_eof() {
+    let (stdout, stderr) = run_repl("def f(x) x + 1;\nextern sin(a);\nglobal g = 2;\nf(g);\n");
+    assert_eq!(
+        stdout,
+        "ready> Parsed a function definition.\n\
+         ready> Parsed an extern.\n\
+         ready> Parsed a global variable.\n\
+         ready> Parsed a top-level expr.\n\
+         ready> \n\
+         Parsed 1 function definition(s), 1 extern(s), 1 global(s), 1 top-level expr(s); 0 error(s).\n"
+    );
+    assert_eq!(stderr, "");
+
+    let (stdout, _) = run_repl("");
+    assert_eq!(
+        stdout,
+        "ready> \nParsed 0 function definition(s), 0 extern(s), 0 global(s), 0 top-level expr(s); 0 error(s).\n"
+    );
+}
+
+#[test]
+fn test_reprompt_after_error() {
+    let (stdout, stderr) = run_repl(")\n1 + 2;\n");
+    assert!(stdout.starts_with("ready> ready> Parsed a top-level expr.\nready> \n"));
+    assert!(stdout.ends_with("1 top-level expr(s); 1 error(s).\n"));
+    assert_eq!(stderr.lines().count(), 1);
+    assert!(stderr.starts_with("error[K0102] <stdin>:0..1: "));
+}
+
+#[test]
+fn test_continuation_lines() {
+    let (stdout, _) = run_repl("def f(x)\n\n  x * 2;\n");
+    assert!(stdout.starts_with("ready> ...> ...> Parsed a function definition.\nready> \n"));
+}
+
+#[test]
+fn test_missing_separator() {
+    // 和 parse_program 一样, 缺少 ';' 时报错, 之后的项照常解析
+    let (stdout, stderr) = run_repl("def f(x) x f(2)\n");
+    assert!(
+        stdout.starts_with(
+            "ready> Parsed a function definition.\nParsed a top-level expr.\nready> \n"
+        )
+    );
+    assert!(stdout.ends_with("1 top-level expr(s); 1 error(s).\n"));
+    assert_eq!(
+        stderr,
+        "error[K0102] <stdin>:11..12: expected one of ';', Eof, '||', '&&', '<', '+', '-', '*', '[', got Identifier at 11..12\n"
+    );
+}
+
+#[test]
+fn test_pending_input_at_eof() {
+    // 最后一行没有换行, 仍然会被处理
+    let (stdout, stderr) = run_repl("extern cos(x);\nf(1");
+    assert!(stdout.contains("Parsed an extern.\nready> ...> \n"));
+    assert!(stdout.ends_with("1 extern(s), 0 global(s), 0 top-level expr(s); 1 error(s).\n"));
+    assert_eq!(
+        stderr,
+        "error[K0103] <stdin>:18..18: unexpected end of input, expected one of ')', ',', '||', '&&', '<', '+', '-', '*', '['\n"
+    );
+
+    let (stdout, stderr) = run_repl("def f(x) x");
+    assert!(stdout.starts_with("ready> Parsed a function definition.\nready> \n"));
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_color_env() {
+    let (_, stderr) = run_repl_with_env(")\n", &[("KALC_COLOR", "always")]);
+    assert!(stderr.starts_with("\u{1b}["));
+    let (_, stderr) = run_repl_with_env(")\n", &[("KALC_COLOR", "never")]);
+    assert!(stderr.starts_with("error[K0102] "));
+    let (_, stderr) = run_repl_with_env("", &[("KALC_COLOR", "sometimes")]);
+    assert_eq!(
+        stderr,
+        "ignoring KALC_COLOR=sometimes: expected always, never or auto\n"
+    );
+}
+
+#[test]
+fn test_default_options_env() {
+    // 命令行上的选项优先
+    #[cfg(feature = "json")]
+    {
+        let opts = [("KALEIDOSCOPE_OPTS", "--error-format=json")];
+        let (_, stderr) = run_repl_with_env(")\n", &opts);
+        assert!(stderr.starts_with("{\"code\":\"K0102\""), "{}", stderr);
+        let output = run(&["--error-format=human"], ")\n", &opts);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.starts_with("error[K0102] <stdin>:0..1: "),
+            "{}",
+            stderr
+        );
+    }
+
+    // --check 只对 fmt 有效
+    let opts = [("KALEIDOSCOPE_OPTS", " --check ")];
+    let output = run(&["fmt"], "def f(x)   x", &opts);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "<stdin> is not formatted\n"
+    );
+    let (_, stderr) = run_repl_with_env("1;\n", &opts);
+    assert_eq!(stderr, "");
+
+    let output = run(&[], "", &[("KALEIDOSCOPE_OPTS", "--verbose")]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("KALEIDOSCOPE_OPTS: unknown option --verbose\n"));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json_errors() {
+    let output = run(&["--error-format=json"], ")\n1.2.3;\n", &[]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "{\"code\":\"K0102\",\"severity\":\"error\",\"file\":\"<stdin>\",\"span\":{\"start\":0,\"end\":1},\
+         \"message\":\"expected one of Identifier, Number, '(', '[', Def, got ')' at 0..1\"}\n\
+         {\"code\":\"K0001\",\"severity\":\"error\",\"file\":\"<stdin>\",\"span\":{\"start\":2,\"end\":7},\
+         \"message\":\"Lexer error:malformed number literal `1.2.3` at 2..7\"}\n"
+    );
+
+    let output = run(&["--error-format=xml"], "", &[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("unknown error format xml: expected human or json\n"));
+}
+
+#[test]
+fn test_check() {
+    let path = std::env::temp_dir().join(format!("kaleidoscope-check-{}.k", std::process::id()));
+    fs::write(&path, "def f(x) y;\nf(1, 2)\n").unwrap();
+    let path_str = path.to_str().unwrap();
+    let human = run(&["check", path_str], "", &[]);
+    #[cfg(feature = "json")]
+    let json = run(&["check", path_str, "--error-format=json"], "", &[]);
+    fs::write(&path, "def f(x) x; f(2)").unwrap();
+    let clean = run(&["check", path_str], "", &[]);
+    fs::remove_file(&path).unwrap();
+
+    assert!(!human.status.success());
+    assert_eq!(
+        String::from_utf8(human.stderr).unwrap(),
+        format!(
+            "error[K0201] {0}:9..10: undefined variable:y\n\
+             error[K0203] {0}:12..13: function f expects 1 argument(s), but 2 were given\n",
+            path_str
+        )
+    );
+    #[cfg(feature = "json")]
+    {
+        let json = String::from_utf8(json.stderr).unwrap();
+        let codes: Vec<_> = json.lines().map(|line| &line[9..14]).collect();
+        assert_eq!(codes, ["K0201", "K0203"]);
+    }
+    assert!(clean.status.success() && clean.stderr.is_empty());
+}